@@ -35,6 +35,20 @@ pub fn cube_mesh() -> Mesh {
 
         ],
         nor: Vec::new(),
+        uv: vec![
+            // top
+            Vec2::new(0., 0.), Vec2::new(1., 0.), Vec2::new(1., 1.), Vec2::new(0., 1.),
+            // bottom
+            Vec2::new(1., 0.), Vec2::new(0., 0.), Vec2::new(0., 1.), Vec2::new(1., 1.),
+            // right
+            Vec2::new(0., 0.), Vec2::new(1., 0.), Vec2::new(1., 1.), Vec2::new(0., 1.),
+            // left
+            Vec2::new(1., 0.), Vec2::new(0., 0.), Vec2::new(0., 1.), Vec2::new(1., 1.),
+            // front
+            Vec2::new(1., 0.), Vec2::new(0., 0.), Vec2::new(0., 1.), Vec2::new(1., 1.),
+            // back
+            Vec2::new(0., 0.), Vec2::new(1., 0.), Vec2::new(1., 1.), Vec2::new(0., 1.),
+        ],
 
         ind: vec![
             0, 1, 2, 2, 3, 0, // top
@@ -46,48 +60,3 @@ pub fn cube_mesh() -> Mesh {
         ]
     }
 }
-
-//     let vertex_data = [
-//         // top (0, 0, 1)
-//         vertex([-1, -1, 1], [0, 0]),
-//         vertex([1, -1, 1], [1, 0]),
-//         vertex([1, 1, 1], [1, 1]),
-//         vertex([-1, 1, 1], [0, 1]),
-//         // bottom (0, 0, -1)
-//         vertex([-1, 1, -1], [1, 0]),
-//         vertex([1, 1, -1], [0, 0]),
-//         vertex([1, -1, -1], [0, 1]),
-//         vertex([-1, -1, -1], [1, 1]),
-//         // right (1, 0, 0)
-//         vertex([1, -1, -1], [0, 0]),
-//         vertex([1, 1, -1], [1, 0]),
-//         vertex([1, 1, 1], [1, 1]),
-//         vertex([1, -1, 1], [0, 1]),
-//         // left (-1, 0, 0)
-//         vertex([-1, -1, 1], [1, 0]),
-//         vertex([-1, 1, 1], [0, 0]),
-//         vertex([-1, 1, -1], [0, 1]),
-//         vertex([-1, -1, -1], [1, 1]),
-//         // front (0, 1, 0)
-//         vertex([1, 1, -1], [1, 0]),
-//         vertex([-1, 1, -1], [0, 0]),
-//         vertex([-1, 1, 1], [0, 1]),
-//         vertex([1, 1, 1], [1, 1]),
-//         // back (0, -1, 0)
-//         vertex([1, -1, 1], [0, 0]),
-//         vertex([-1, -1, 1], [1, 0]),
-//         vertex([-1, -1, -1], [1, 1]),
-//         vertex([1, -1, -1], [0, 1]),
-//     ];
-
-//     let index_data: &[u16] = &[
-//         0, 1, 2, 2, 3, 0, // top
-//         4, 5, 6, 6, 7, 4, // bottom
-//         8, 9, 10, 10, 11, 8, // right
-//         12, 13, 14, 14, 15, 12, // left
-//         16, 17, 18, 18, 19, 16, // front
-//         20, 21, 22, 22, 23, 20, // back
-//     ];
-
-//     (vertex_data.to_vec(), index_data.to_vec())
-// }
\ No newline at end of file
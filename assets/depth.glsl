@@ -0,0 +1,25 @@
+#shader vertex
+#version 140
+
+in vec3 position;
+#include "instancing.glsl"
+
+out float z_itpl;
+
+uniform mat4 u_view_proj;
+
+void main() {
+    gl_Position = u_view_proj * i_model * vec4(position, 1.0);
+    z_itpl = 0.5 + gl_Position.z/gl_Position.w/2.0;
+}
+
+
+#shader fragment
+#version 140
+
+in float z_itpl;
+out vec4 color;
+
+void main() {
+    color = vec4(z_itpl, z_itpl, z_itpl, 1.0);
+}
@@ -0,0 +1,12 @@
+// model matrices of the vertex stages, included after #version,
+// the renderer compiles a second program with INSTANCED defined for the instance groups
+
+#ifdef INSTANCED
+// per instance
+in mat4 i_model;
+in mat3 i_normal;
+#else
+// the merged batch is already in world space
+const mat4 i_model = mat4(1.0);
+const mat3 i_normal = mat3(1.0);
+#endif
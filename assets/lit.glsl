@@ -0,0 +1,21 @@
+#shader vertex
+#version 140
+
+#include "scene_vertex.glsl"
+
+
+#shader fragment
+#version 140
+
+#include "scene_fragment.glsl"
+
+// keep in sync with light::SceneLight::irradiance
+void main() {
+    vec3 normal = surface_normal();
+    vec3 light = vec3(0.0);
+    for (int i = 0; i < u_light_count; i++) {
+        float visibility = u_light_shadowed[i] ? shadow_visibility(v_position) : 1.0;
+        light += u_light_radiance[i] * diffuse(i, v_position, normal) * visibility;
+    }
+    color = vec4(base_color() * light, 1.0);
+}
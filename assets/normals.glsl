@@ -0,0 +1,27 @@
+#shader vertex
+#version 140
+
+in vec3 position;
+in vec3 normal;
+#include "instancing.glsl"
+
+out vec3 v_normal;
+
+uniform mat4 u_view_proj;
+
+void main() {
+    gl_Position = u_view_proj * i_model * vec4(position, 1.0);
+    v_normal = i_normal * normal;
+}
+
+
+#shader fragment
+#version 140
+
+in vec3 v_normal;
+out vec4 color;
+
+// world space normals packed into 0..1 for the outline pass
+void main() {
+    color = vec4(normalize(v_normal) * 0.5 + 0.5, 1.0);
+}
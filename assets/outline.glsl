@@ -0,0 +1,68 @@
+#shader vertex
+#version 140
+
+in vec2 position;
+in vec2 uv;
+out vec2 uvi;
+
+void main() {
+    uvi = uv;
+    gl_Position = vec4(position, 0.0, 1.0);
+}
+
+
+#shader fragment
+#version 140
+
+in vec2 uvi;
+out vec4 color;
+
+uniform sampler2D tex;
+uniform sampler2D u_depth;
+// packed by assets/normals.glsl
+uniform sampler2D u_normal;
+
+uniform float u_depth_threshold;
+uniform float u_normal_threshold;
+
+// per edge kind: x is the darken factor, or ink when w is 1
+uniform vec4 u_silhouette_color;
+uniform vec4 u_crease_color;
+
+vec4 edge_color(vec4 base, vec4 outline) {
+    return outline.w > 0.5 ? vec4(outline.rgb, 1.0) : vec4(base.rgb * outline.x, base.a);
+}
+
+// keep in sync with outline::classify
+void main() {
+    ivec2 size = textureSize(u_depth, 0);
+    ivec2 p = ivec2(gl_FragCoord.xy);
+    vec4 base = texelFetch(tex, p, 0);
+    float depth = texelFetch(u_depth, p, 0).r;
+    vec3 normal = texelFetch(u_normal, p, 0).rgb * 2.0 - 1.0;
+    color = base;
+
+    // nothing was drawn here
+    if (depth >= 1.0) {
+        return;
+    }
+
+    float cos_threshold = cos(u_normal_threshold);
+    bool crease = false;
+    ivec2 offsets[4] = ivec2[4](ivec2(1, 0), ivec2(-1, 0), ivec2(0, 1), ivec2(0, -1));
+    for (int i = 0; i < 4; i++) {
+        ivec2 q = clamp(p + offsets[i], ivec2(0), size - 1);
+        float further = texelFetch(u_depth, q, 0).r - depth;
+        if (further > u_depth_threshold) {
+            color = edge_color(base, u_silhouette_color);
+            return;
+        }
+        vec3 n = texelFetch(u_normal, q, 0).rgb * 2.0 - 1.0;
+        if (further >= 0.0 && dot(normal, n) < cos_threshold) {
+            crease = true;
+        }
+    }
+    if (crease) {
+        color = edge_color(base, u_crease_color);
+    }
+}
@@ -0,0 +1,66 @@
+#shader vertex
+#version 140
+
+in vec2 position;
+in vec2 uv;
+out vec2 uvi;
+
+void main() {
+    uvi = uv;
+    gl_Position = vec4(position, 0.0, 1.0);
+}
+
+
+#shader fragment
+#version 140
+
+in vec2 uvi;
+out vec4 color;
+
+uniform sampler2D tex;
+// one column per color, OKLab in row 0 and linear rgb in row 1
+uniform sampler2D u_palette;
+// ordered dither thresholds in -0.5..0.5, tiled over the low-res target
+uniform sampler2D u_threshold;
+// OKLab lightness a threshold of 1 moves the color by
+uniform float u_dither_strength;
+
+// keep in sync with palette::linear_srgb_to_oklab
+vec3 linear_srgb_to_oklab(vec3 c) {
+    c = max(c, vec3(0.0));
+    float l = 0.4122214708 * c.r + 0.5363325363 * c.g + 0.0514459929 * c.b;
+    float m = 0.2119034982 * c.r + 0.6806995451 * c.g + 0.1073969566 * c.b;
+    float s = 0.0883024619 * c.r + 0.2817188376 * c.g + 0.6299787005 * c.b;
+    vec3 lms = pow(vec3(l, m, s), vec3(1.0 / 3.0));
+    return vec3(
+        0.2104542553 * lms.x + 0.7936177850 * lms.y - 0.0040720468 * lms.z,
+        1.9779984951 * lms.x - 2.4285922050 * lms.y + 0.4505937099 * lms.z,
+        0.0259040371 * lms.x + 0.7827717662 * lms.y - 0.8086757660 * lms.z);
+}
+
+void main() {
+    vec4 src = texture(tex, uvi);
+    // fully transparent pixels are left alone, keep in sync with dither::quantize
+    if (src.a <= 0.0) {
+        color = src;
+        return;
+    }
+    vec3 lab = linear_srgb_to_oklab(src.rgb);
+    // gl_FragCoord is in low-res pixels here, so the pattern stays on the pixel grid
+    ivec2 tile = textureSize(u_threshold, 0);
+    lab.x += texelFetch(u_threshold, ivec2(gl_FragCoord.xy) % tile, 0).r * u_dither_strength;
+
+    int count = textureSize(u_palette, 0).x;
+    int nearest = 0;
+    float nearest_dist = 1e10;
+    for (int i = 0; i < count; i++) {
+        vec3 d = texelFetch(u_palette, ivec2(i, 0), 0).rgb - lab;
+        float dist = dot(d, d);
+        if (dist < nearest_dist) {
+            nearest_dist = dist;
+            nearest = i;
+        }
+    }
+
+    color = vec4(texelFetch(u_palette, ivec2(nearest, 1), 0).rgb, src.a);
+}
@@ -0,0 +1,77 @@
+// lights, shadow and material inputs of the scene fragment shaders, included after #version
+
+// keep in sync with light::MAX_LIGHTS
+#define MAX_LIGHTS 8
+#define LIGHT_AMBIENT 0
+#define LIGHT_DIRECTIONAL 1
+#define LIGHT_POINT 2
+
+in vec3 v_position;
+in vec3 v_normal;
+in vec2 v_uv;
+out vec4 color;
+
+uniform int u_light_count;
+uniform int u_light_kind[MAX_LIGHTS];
+// towards the light for directional lights, position for point lights
+uniform vec3 u_light_vector[MAX_LIGHTS];
+uniform vec3 u_light_radiance[MAX_LIGHTS];
+uniform float u_light_range[MAX_LIGHTS];
+// the light the shadow map is rendered from
+uniform bool u_light_shadowed[MAX_LIGHTS];
+
+uniform mat4 u_light_view_proj;
+uniform sampler2D u_shadow_map;
+// half the side of the filter kernel
+uniform int u_shadow_pcf;
+uniform float u_shadow_bias;
+
+// linear, multiplies the texture
+uniform vec3 u_tint;
+uniform sampler2D u_base_color;
+uniform bool u_has_base_color;
+
+// keep in sync with light::SceneLight::diffuse
+float diffuse(int i, vec3 position, vec3 normal) {
+    if (u_light_kind[i] == LIGHT_AMBIENT) {
+        return 1.0;
+    }
+    if (u_light_kind[i] == LIGHT_DIRECTIONAL) {
+        return max(dot(normal, u_light_vector[i]), 0.0);
+    }
+    vec3 to_light = u_light_vector[i] - position;
+    float distance = length(to_light);
+    float falloff = clamp(1.0 - pow(distance / max(u_light_range[i], 1e-6), 2.0), 0.0, 1.0);
+    float n_dot_l = distance > 0.0 ? max(dot(normal, to_light / distance), 0.0) : 0.0;
+    return n_dot_l * falloff * falloff;
+}
+
+// keep in sync with shadow::ShadowMap::visibility
+float shadow_visibility(vec3 position) {
+    vec4 p = u_light_view_proj * vec4(position, 1.0);
+    vec3 uvz = p.xyz / p.w * 0.5 + 0.5;
+    if (any(lessThan(uvz, vec3(0.0))) || any(greaterThan(uvz, vec3(1.0)))) {
+        return 1.0;
+    }
+    ivec2 size = textureSize(u_shadow_map, 0);
+    ivec2 texel = ivec2(uvz.xy * vec2(size));
+    float lit = 0.0;
+    for (int dy = -u_shadow_pcf; dy <= u_shadow_pcf; dy++) {
+        for (int dx = -u_shadow_pcf; dx <= u_shadow_pcf; dx++) {
+            ivec2 t = clamp(texel + ivec2(dx, dy), ivec2(0), size - 1);
+            lit += uvz.z - u_shadow_bias <= texelFetch(u_shadow_map, t, 0).r ? 1.0 : 0.0;
+        }
+    }
+    float side = float(2 * u_shadow_pcf + 1);
+    return lit / (side * side);
+}
+
+// keep in sync with material::CpuMaterial::color_at
+vec3 base_color() {
+    return u_has_base_color ? u_tint * texture(u_base_color, v_uv).rgb : u_tint;
+}
+
+// zero for degenerate normals, they only get the ambient light
+vec3 surface_normal() {
+    return length(v_normal) > 0.0 ? normalize(v_normal) : vec3(0.0);
+}
@@ -0,0 +1,20 @@
+// vertex stage of assets/lit.glsl and assets/toon.glsl, included after #version
+
+in vec3 position;
+in vec3 normal;
+in vec2 uv;
+#include "instancing.glsl"
+
+out vec3 v_position;
+out vec3 v_normal;
+out vec2 v_uv;
+
+uniform mat4 u_view_proj;
+
+void main() {
+    vec4 world = i_model * vec4(position, 1.0);
+    gl_Position = u_view_proj * world;
+    v_position = world.xyz;
+    v_normal = i_normal * normal;
+    v_uv = uv;
+}
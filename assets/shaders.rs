@@ -7,10 +7,14 @@ pub static QUAD_VSH_SRC: &str = r#"
     out vec2 uvi;
 
     uniform float t;
+    // texture size over target size, the margin around the low-res target ends up outside the viewport
+    uniform vec2 u_scale;
+    // sub-texel camera snapping leftover, in NDC
+    uniform vec2 u_offset;
 
     void main() {
         uvi = uv;
-        gl_Position = vec4(position, 0.0, 1.0);
+        gl_Position = vec4(position * u_scale + u_offset, 0.0, 1.0);
     }
 "#;
 
@@ -74,28 +78,3 @@ pub static WIREFRAME_FSH_SRC: &str = r#"
     }
 "#;
 
-
-// 
-
-pub static DIFFUSE_VSH_SRC: &str = r#"
-    #version 140
-    in vec3 position;
-
-    out float z_itpl;
-
-    void main() {
-        z_itpl = 0.5 + position.z/2.0;
-        gl_Position = vec4(position, 1.0);
-    }
-"#;
-
-pub static DIFFUSE_FSH_SRC: &str = r#"
-    #version 140
-
-    in float z_itpl;
-    out vec4 color;
-
-    void main() {
-        color = vec4(z_itpl, z_itpl, z_itpl, 1.0);
-    }
-"#;
\ No newline at end of file
@@ -0,0 +1,47 @@
+#shader vertex
+#version 140
+
+#include "scene_vertex.glsl"
+
+
+#shader fragment
+#version 140
+
+#include "scene_fragment.glsl"
+
+uniform int u_bands;
+// linear colors, darkest first, used instead of the bands when u_ramp_size > 0
+uniform sampler2D u_ramp;
+uniform int u_ramp_size;
+uniform vec3 u_rim_color;
+uniform float u_rim_strength;
+uniform float u_rim_width;
+// towards the camera
+uniform vec3 u_view_dir;
+
+// keep in sync with toon::band and toon::ramp_color
+vec3 step_diffuse(float d) {
+    if (u_ramp_size > 0) {
+        int i = min(int(d * float(u_ramp_size)), u_ramp_size - 1);
+        return texelFetch(u_ramp, ivec2(i, 0), 0).rgb;
+    }
+    float bands = float(u_bands);
+    return vec3(clamp(ceil(d * bands), 0.0, bands) / bands);
+}
+
+// keep in sync with toon::rim
+float rim(vec3 normal) {
+    return length(v_normal) > 0.0 && dot(normal, u_view_dir) < u_rim_width ? 1.0 : 0.0;
+}
+
+void main() {
+    vec3 normal = surface_normal();
+    vec3 light = vec3(0.0);
+    for (int i = 0; i < u_light_count; i++) {
+        // shadowed surfaces fall into the lowest band
+        float visibility = u_light_shadowed[i] ? shadow_visibility(v_position) : 1.0;
+        float d = diffuse(i, v_position, normal) * visibility;
+        light += u_light_kind[i] == LIGHT_AMBIENT ? u_light_radiance[i] : u_light_radiance[i] * step_diffuse(d);
+    }
+    color = vec4(base_color() * (light + u_rim_color * u_rim_strength * rim(normal)), 1.0);
+}
@@ -0,0 +1,349 @@
+use std::sync::OnceLock;
+
+use glam::*;
+
+use crate::palette::{Palette, linear_srgb_to_oklab};
+
+// how far in OKLab lightness an ordered threshold of ±0.5 moves a color at strength 1
+pub const ORDERED_SPREAD: f32 = 0.3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DitherPattern {
+    None,
+    Bayer2,
+    Bayer4,
+    Bayer8,
+    BlueNoise,
+    // error diffusion is serial, so these two only run on the CPU path
+    FloydSteinberg,
+    Atkinson,
+}
+
+impl DitherPattern {
+    pub const ALL: [DitherPattern; 7] = [
+        DitherPattern::None, DitherPattern::Bayer2, DitherPattern::Bayer4, DitherPattern::Bayer8,
+        DitherPattern::BlueNoise, DitherPattern::FloydSteinberg, DitherPattern::Atkinson,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DitherPattern::None => "None",
+            DitherPattern::Bayer2 => "Bayer 2x2",
+            DitherPattern::Bayer4 => "Bayer 4x4",
+            DitherPattern::Bayer8 => "Bayer 8x8",
+            DitherPattern::BlueNoise => "Blue noise",
+            DitherPattern::FloydSteinberg => "Floyd-Steinberg",
+            DitherPattern::Atkinson => "Atkinson",
+        }
+    }
+
+    pub fn is_error_diffusion(&self) -> bool {
+        matches!(self, DitherPattern::FloydSteinberg | DitherPattern::Atkinson)
+    }
+
+    // None for the error diffusion patterns
+    pub fn threshold_map(&self) -> Option<&'static ThresholdMap> {
+        static BAYER2: OnceLock<ThresholdMap> = OnceLock::new();
+        static BAYER4: OnceLock<ThresholdMap> = OnceLock::new();
+        static BAYER8: OnceLock<ThresholdMap> = OnceLock::new();
+        static BLUE_NOISE: OnceLock<ThresholdMap> = OnceLock::new();
+        static NONE: OnceLock<ThresholdMap> = OnceLock::new();
+        match self {
+            DitherPattern::None => Some(NONE.get_or_init(|| ThresholdMap { size: 1, values: vec![0.] })),
+            DitherPattern::Bayer2 => Some(BAYER2.get_or_init(|| ThresholdMap::bayer(2))),
+            DitherPattern::Bayer4 => Some(BAYER4.get_or_init(|| ThresholdMap::bayer(4))),
+            DitherPattern::Bayer8 => Some(BAYER8.get_or_init(|| ThresholdMap::bayer(8))),
+            DitherPattern::BlueNoise => Some(BLUE_NOISE.get_or_init(|| ThresholdMap::blue_noise(64))),
+            DitherPattern::FloydSteinberg | DitherPattern::Atkinson => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DitherSettings {
+    pub pattern: DitherPattern,
+    // 0 is plain quantization
+    pub strength: f32,
+}
+
+impl Default for DitherSettings {
+    fn default() -> Self {
+        DitherSettings { pattern: DitherPattern::None, strength: 0.75 }
+    }
+}
+
+// square tile of thresholds in -0.5..0.5, repeated over the low-res target
+#[derive(Clone, Debug, PartialEq)]
+pub struct ThresholdMap {
+    pub size: u32,
+    pub values: Vec<f32>,
+}
+
+impl ThresholdMap {
+    // `size` must be a power of two
+    pub fn bayer(size: u32) -> Self {
+        assert!(size.is_power_of_two(), "bayer matrix size must be a power of two");
+        let mut ranks = vec![0_u32];
+        let mut n = 1;
+        while n < size {
+            // M(2n) = [[4M, 4M+2], [4M+3, 4M+1]]
+            let mut next = vec![0; (4 * n * n) as usize];
+            for y in 0..n {
+                for x in 0..n {
+                    let r = 4 * ranks[(y * n + x) as usize];
+                    next[(y * 2 * n + x) as usize] = r;
+                    next[(y * 2 * n + x + n) as usize] = r + 2;
+                    next[((y + n) * 2 * n + x) as usize] = r + 3;
+                    next[((y + n) * 2 * n + x + n) as usize] = r + 1;
+                }
+            }
+            ranks = next;
+            n *= 2;
+        }
+        ThresholdMap::from_ranks(size, &ranks)
+    }
+
+    // void-and-cluster (Ulichney 1993), deterministic so the look doesn't change between runs
+    pub fn blue_noise(size: u32) -> Self {
+        // smaller maps get no initial points to move around
+        assert!(size >= 4, "blue noise maps need to be at least 4x4, got {}", size);
+        let len = (size * size) as usize;
+
+        // toroidal gaussian, sigma 1.5 like the paper
+        let kernel: Vec<f32> = (0..len).map(|i| {
+            let (x, y) = ((i as u32 % size) as i32, (i as u32 / size) as i32);
+            let dx = x.min(size as i32 - x) as f32;
+            let dy = y.min(size as i32 - y) as f32;
+            (-(dx * dx + dy * dy) / (2. * 1.5 * 1.5)).exp()
+        }).collect();
+        let offset = |a: usize, b: usize| {
+            let (ax, ay) = (a as u32 % size, a as u32 / size);
+            let (bx, by) = (b as u32 % size, b as u32 / size);
+            (((by + size - ay) % size) * size + (bx + size - ax) % size) as usize
+        };
+
+        struct Pattern { ones: Vec<bool>, energy: Vec<f32> }
+        impl Pattern {
+            fn set(&mut self, i: usize, one: bool, kernel: &[f32], offset: &dyn Fn(usize, usize) -> usize) {
+                self.ones[i] = one;
+                let sign = if one { 1. } else { -1. };
+                for (j, e) in self.energy.iter_mut().enumerate() {
+                    *e += sign * kernel[offset(i, j)];
+                }
+            }
+            fn tightest_cluster(&self) -> usize {
+                (0..self.ones.len()).filter(|&i| self.ones[i])
+                    .max_by(|&a, &b| self.energy[a].total_cmp(&self.energy[b])).unwrap()
+            }
+            fn largest_void(&self) -> usize {
+                (0..self.ones.len()).filter(|&i| !self.ones[i])
+                    .min_by(|&a, &b| self.energy[a].total_cmp(&self.energy[b])).unwrap()
+            }
+        }
+
+        // initial pattern from a fixed xorshift seed, about a tenth of the pixels set
+        let mut initial = Pattern { ones: vec![false; len], energy: vec![0.; len] };
+        let mut seed = 0x2545_f491_u32;
+        let mut count = 0;
+        while count < len / 10 {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            let i = seed as usize % len;
+            if !initial.ones[i] {
+                initial.set(i, true, &kernel, &offset);
+                count += 1;
+            }
+        }
+
+        // move points from clusters into voids until that stops changing anything,
+        // bounded because the swap can flip between two equally good states forever
+        for _ in 0..len {
+            let cluster = initial.tightest_cluster();
+            initial.set(cluster, false, &kernel, &offset);
+            let void = initial.largest_void();
+            initial.set(void, true, &kernel, &offset);
+            if void == cluster {
+                break;
+            }
+        }
+
+        let mut ranks = vec![0_u32; len];
+
+        // ranks below the initial count, removing clusters first
+        let mut pattern = Pattern { ones: initial.ones.clone(), energy: initial.energy.clone() };
+        for rank in (0..count).rev() {
+            let cluster = pattern.tightest_cluster();
+            pattern.set(cluster, false, &kernel, &offset);
+            ranks[cluster] = rank as u32;
+        }
+
+        // the rest by filling the largest voids
+        for rank in count..len {
+            let void = initial.largest_void();
+            initial.set(void, true, &kernel, &offset);
+            ranks[void] = rank as u32;
+        }
+
+        ThresholdMap::from_ranks(size, &ranks)
+    }
+
+    fn from_ranks(size: u32, ranks: &[u32]) -> Self {
+        let n = ranks.len() as f32;
+        ThresholdMap { size, values: ranks.iter().map(|&r| (r as f32 + 0.5) / n - 0.5).collect() }
+    }
+
+    // `x` and `y` are low-res pixel coordinates, which keeps the pattern locked to the pixel grid
+    pub fn get(&self, x: u32, y: u32) -> f32 {
+        self.values[((y % self.size) * self.size + x % self.size) as usize]
+    }
+}
+
+// CPU reference of the palette pass, `colors` is linear rgba with rows top to bottom.
+// Fully transparent pixels are left alone so the upscale still shows what's behind them.
+pub fn quantize(colors: &mut [Vec4], size: UVec2, palette: &Palette, dither: DitherSettings) {
+    if palette.is_empty() {
+        return;
+    }
+    match dither.pattern.threshold_map() {
+        Some(map) => {
+            for y in 0..size.y {
+                for x in 0..size.x {
+                    let c = &mut colors[(y * size.x + x) as usize];
+                    if c.w <= 0. {
+                        continue;
+                    }
+                    // gl_FragCoord counts rows from the bottom
+                    let t = map.get(x, size.y - 1 - y);
+                    let mut lab = linear_srgb_to_oklab(c.xyz());
+                    lab.x += t * dither.strength * ORDERED_SPREAD;
+                    let nearest = palette.nearest_index_lab(lab).unwrap();
+                    *c = palette.linear()[nearest].extend(c.w);
+                }
+            }
+        }
+        None => diffuse_error(colors, size, palette, dither),
+    }
+}
+
+// (dx, dy, weight) of the neighbours that get a share of the error
+fn diffusion_kernel(pattern: DitherPattern) -> &'static [(i32, i32, f32)] {
+    match pattern {
+        DitherPattern::FloydSteinberg => &[
+            (1, 0, 7. / 16.),
+            (-1, 1, 3. / 16.), (0, 1, 5. / 16.), (1, 1, 1. / 16.),
+        ],
+        // only passes on 3/4 of the error, which keeps highlights and shadows clean
+        DitherPattern::Atkinson => &[
+            (1, 0, 1. / 8.), (2, 0, 1. / 8.),
+            (-1, 1, 1. / 8.), (0, 1, 1. / 8.), (1, 1, 1. / 8.),
+            (0, 2, 1. / 8.),
+        ],
+        _ => &[],
+    }
+}
+
+// error is carried in OKLab, the space the nearest color is picked in
+fn diffuse_error(colors: &mut [Vec4], size: UVec2, palette: &Palette, dither: DitherSettings) {
+    let kernel = diffusion_kernel(dither.pattern);
+    let mut lab: Vec<Vec3> = colors.iter().map(|c| linear_srgb_to_oklab(c.xyz())).collect();
+    for y in 0..size.y {
+        for x in 0..size.x {
+            let i = (y * size.x + x) as usize;
+            if colors[i].w <= 0. {
+                continue;
+            }
+            let nearest = palette.nearest_index_lab(lab[i]).unwrap();
+            let error = (lab[i] - palette.lab()[nearest]) * dither.strength;
+            colors[i] = palette.linear()[nearest].extend(colors[i].w);
+
+            for &(dx, dy, weight) in kernel {
+                let (nx, ny) = (x as i32 + dx, y as i32 + dy);
+                if nx < 0 || nx >= size.x as i32 || ny >= size.y as i32 {
+                    continue;
+                }
+                lab[(ny as u32 * size.x + nx as u32) as usize] += error * weight;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted_ranks(map: &ThresholdMap) -> Vec<u32> {
+        let n = map.values.len() as f32;
+        let mut ranks: Vec<u32> = map.values.iter().map(|&v| ((v + 0.5) * n - 0.5).round() as u32).collect();
+        ranks.sort();
+        ranks
+    }
+
+    #[test]
+    fn bayer_matches_reference() {
+        let ranks: Vec<u32> = ThresholdMap::bayer(4).values.iter().map(|&v| ((v + 0.5) * 16. - 0.5).round() as u32).collect();
+        assert_eq!(ranks, vec![0, 8, 2, 10, 12, 4, 14, 6, 3, 11, 1, 9, 15, 7, 13, 5]);
+    }
+
+    #[test]
+    fn maps_are_permutations() {
+        for pattern in [DitherPattern::Bayer2, DitherPattern::Bayer8, DitherPattern::BlueNoise] {
+            let map = pattern.threshold_map().unwrap();
+            assert_eq!(sorted_ranks(map), (0..map.values.len() as u32).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn smallest_blue_noise_is_a_permutation() {
+        let map = ThresholdMap::blue_noise(4);
+        assert_eq!(sorted_ranks(&map), (0..16).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn blue_noise_has_no_clumps() {
+        // the darkest eighth of the thresholds should be spread out, no two of them touching
+        let map = DitherPattern::BlueNoise.threshold_map().unwrap();
+        let size = map.size as i32;
+        let dark = |x: i32, y: i32| map.get(x.rem_euclid(size) as u32, y.rem_euclid(size) as u32) < -0.5 + 1. / 8.;
+        for y in 0..size {
+            for x in 0..size {
+                if dark(x, y) {
+                    assert!(!dark(x + 1, y) && !dark(x, y + 1), "clump at {} {}", x, y);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn ordered_dither_mixes_colors_for_midtones() {
+        let palette = Palette::from_hex(&[0x000000, 0xffffff]);
+        let size = UVec2::new(4, 4);
+        // perceptual mid grey, plain quantization would make it all one color
+        let grey = crate::raster::srgb_to_linear(0.5);
+        let mut colors = vec![Vec4::new(grey, grey, grey, 1.); 16];
+        quantize(&mut colors, size, &palette, DitherSettings { pattern: DitherPattern::Bayer4, strength: 1. / ORDERED_SPREAD });
+        let white = colors.iter().filter(|c| c.x == 1.).count();
+        assert!((4..=12).contains(&white), "{} white pixels", white);
+    }
+
+    #[test]
+    fn error_diffusion_preserves_average() {
+        let palette = Palette::from_hex(&[0x000000, 0xffffff]);
+        let size = UVec2::new(16, 16);
+        let grey = crate::raster::srgb_to_linear(0.5);
+        for pattern in [DitherPattern::FloydSteinberg, DitherPattern::Atkinson] {
+            let mut colors = vec![Vec4::new(grey, grey, grey, 1.); 256];
+            quantize(&mut colors, size, &palette, DitherSettings { pattern, strength: 1. });
+            let white = colors.iter().filter(|c| c.x == 1.).count();
+            assert!((64..=192).contains(&white), "{}: {} white pixels", pattern.name(), white);
+        }
+    }
+
+    #[test]
+    fn transparent_pixels_are_skipped() {
+        let palette = Palette::from_hex(&[0xffffff]);
+        let mut colors = vec![Vec4::ZERO; 4];
+        quantize(&mut colors, UVec2::new(2, 2), &palette, DitherSettings { pattern: DitherPattern::FloydSteinberg, strength: 1. });
+        assert!(colors.iter().all(|&c| c == Vec4::ZERO));
+    }
+}
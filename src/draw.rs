@@ -1,43 +1,153 @@
 
 use std::marker::PhantomData;
 use std::mem::MaybeUninit;
+use std::collections::HashMap;
 
-use glium::framebuffer::DepthRenderBuffer;
+use std::rc::Rc;
+
+use glium::backend::Facade;
+use glium::framebuffer::{DepthRenderBuffer, SimpleFrameBuffer};
+use glium::texture::{DepthFormat, DepthTexture2d, SrgbTexture2d, Texture2d};
 use glium::glutin::{self, event_loop};
-use glium::{VertexBuffer, IndexBuffer, index::PrimitiveType, Display, Surface, Program, uniforms::Uniforms, draw_parameters::DrawParameters, glutin::event_loop::{EventLoop, ControlFlow}};
+use glium::{VertexBuffer, IndexBuffer, index::PrimitiveType, Display, Surface, Program, uniforms::{Uniforms, UniformValue, EmptyUniforms}, draw_parameters::DrawParameters, glutin::event_loop::{EventLoop, ControlFlow}};
 use glam::*;
 
 use crate::loading::*;
+use crate::headless::*;
+use crate::palette::Palette;
+use crate::dither::*;
+use crate::outline::OutlineSettings;
+use crate::toon;
+use crate::shadow::ShadowSettings;
+use crate::material::MaterialUniforms;
 
 pub struct Mesh {
     pub pos: Vec<Vec3>,
     pub nor: Vec<Vec3>,
+    // texture coordinates, empty when the mesh has none, v = 0 is the top row of the image
+    pub uv: Vec<Vec2>,
     pub ind: Vec<u32>,
 }
 
-pub struct MeshRenderData<'a> {
-    pub mesh: &'a Mesh,
+impl Mesh {
+    pub fn has_uv(&self) -> bool {
+        !self.uv.is_empty() && self.uv.len() == self.pos.len()
+    }
+
+    // model space box around the positions, None for an empty mesh
+    pub fn bounds(&self) -> Option<(Vec3, Vec3)> {
+        let first = *self.pos.first()?;
+        Some(self.pos.iter().fold((first, first), |(min, max), &p| (min.min(p), max.max(p))))
+    }
+
+    // every triangle gets its own vertices with the face normal
+    pub fn generate_flat_normals(&mut self) {
+        let mut pos = Vec::with_capacity(self.ind.len());
+        let mut nor = Vec::with_capacity(self.ind.len());
+        let mut uv = Vec::new();
+        for tri in self.ind.chunks_exact(3) {
+            let [a, b, c] = [tri[0], tri[1], tri[2]].map(|i| self.pos[i as usize]);
+            let n = (b - a).cross(c - a).normalize_or_zero();
+            pos.extend([a, b, c]);
+            nor.extend([n, n, n]);
+            if self.has_uv() {
+                uv.extend([tri[0], tri[1], tri[2]].map(|i| self.uv[i as usize]));
+            }
+        }
+        self.ind = (0..pos.len() as u32).collect();
+        self.pos = pos;
+        self.nor = nor;
+        self.uv = uv;
+    }
+
+    // averages the normals of faces around a vertex position, faces meeting at an angle
+    // above `max_angle` (radians) keep a hard edge and get split vertices
+    pub fn generate_smooth_normals(&mut self, max_angle: f32) {
+        let cos_max = max_angle.cos();
+
+        // area weighted face normals
+        let face_nor: Vec<Vec3> = self.ind.chunks_exact(3)
+            .map(|tri| {
+                let [a, b, c] = [tri[0], tri[1], tri[2]].map(|i| self.pos[i as usize]);
+                (b - a).cross(c - a)
+            })
+            .collect();
+
+        // vertices sharing a position are smoothed together even if they have different indices
+        let mut faces_at: std::collections::HashMap<[u32; 3], Vec<usize>> = Default::default();
+        for (corner, &i) in self.ind.iter().enumerate() {
+            let key = self.pos[i as usize].to_array().map(f32::to_bits);
+            faces_at.entry(key).or_default().push(corner / 3);
+        }
+
+        let mut pos = Vec::with_capacity(self.pos.len());
+        let mut nor = Vec::with_capacity(self.pos.len());
+        let mut uv = Vec::new();
+        let mut ind = Vec::with_capacity(self.ind.len());
+        let mut vertices: std::collections::HashMap<(u32, [u32; 3]), u32> = Default::default();
+        for (corner, &i) in self.ind.iter().enumerate() {
+            let p = self.pos[i as usize];
+            let face_n = face_nor[corner / 3].normalize_or_zero();
+            let n = faces_at[&p.to_array().map(f32::to_bits)].iter()
+                .map(|&f| face_nor[f])
+                .filter(|other| other.normalize_or_zero().dot(face_n) >= cos_max)
+                .fold(Vec3::ZERO, |acc, other| acc + other)
+                .normalize_or_zero();
+
+            let new_i = *vertices.entry((i, n.to_array().map(f32::to_bits))).or_insert_with(|| {
+                pos.push(p);
+                nor.push(n);
+                if self.has_uv() {
+                    uv.push(self.uv[i as usize]);
+                }
+                (pos.len() - 1) as u32
+            });
+            ind.push(new_i);
+        }
+        self.pos = pos;
+        self.nor = nor;
+        self.uv = uv;
+        self.ind = ind;
+    }
+}
+
+// gpu copy of a single mesh in model space, drawn once per instance
+#[derive(Debug)]
+pub struct MeshRenderData {
     pub vps_vbo: VertexBuffer<MeshRenderDataVertexPos>, 
     pub nor_vbo: VertexBuffer<MeshRenderDataVertexNor>, 
+    pub uv_vbo: VertexBuffer<MeshRenderDataVertexUv>, 
     pub ibo: IndexBuffer<u32>,
 }
 
-impl<'a> MeshRenderData<'a> {
-    pub fn new(display: &Display, mesh: &'a Mesh) -> Self {
+impl MeshRenderData {
+    pub fn new(display: &GlContext, mesh: &Mesh) -> Self {
         // let data: Vec<MeshRenderDataVertexPos> = unsafe {std::mem::transmute(mesh.vps)};
         let data = unsafe {
             std::slice::from_raw_parts(mesh.pos.as_ptr() as *const MeshRenderDataVertexPos, mesh.pos.len())
         };
+        let nor: Vec<MeshRenderDataVertexNor> = if mesh.nor.len() == mesh.pos.len() {
+            mesh.nor.iter().map(|&n| MeshRenderDataVertexNor{normal: n.into()}).collect()
+        } else {
+            vec![MeshRenderDataVertexNor{normal: Vec3::ZERO.into()}; mesh.pos.len()]
+        };
+        let uv: Vec<MeshRenderDataVertexUv> = if mesh.has_uv() {
+            mesh.uv.iter().map(|&uv| MeshRenderDataVertexUv{uv: uv.into()}).collect()
+        } else {
+            vec![MeshRenderDataVertexUv{uv: [0., 0.]}; mesh.pos.len()]
+        };
         MeshRenderData {
-            mesh: mesh,
             vps_vbo: VertexBuffer::new(display, &data).unwrap(),
-            nor_vbo: VertexBuffer::new(display, &vec![MeshRenderDataVertexNor{normal: Vec3::ZERO.into()}; mesh.pos.len()]).unwrap(),
+            nor_vbo: VertexBuffer::new(display, &nor).unwrap(),
+            uv_vbo: VertexBuffer::new(display, &uv).unwrap(),
             ibo: IndexBuffer::new(display, PrimitiveType::TrianglesList, &mesh.ind).unwrap(),
         }
     }
 
-    pub fn render<S: Surface, U: Uniforms>(self: &Self, surface: &mut S, shader: &Program, uniforms: &U, draw_parameters: &DrawParameters) {
-        surface.draw(&self.vps_vbo, &self.ibo, &shader, uniforms,
+    pub fn render<S: Surface, U: Uniforms>(&self, surface: &mut S, instances: glium::vertex::PerInstance, 
+        shader: &Program, uniforms: &U, draw_parameters: &DrawParameters) 
+    {
+        surface.draw((&self.vps_vbo, &self.nor_vbo, &self.uv_vbo, instances), &self.ibo, &shader, uniforms,
                         draw_parameters).unwrap();
     }
 }
@@ -53,6 +163,31 @@ pub struct MeshRenderDataVertexNor {
     pub normal: [f32; 3],
 }
 implement_vertex!(MeshRenderDataVertexNor, normal);
+#[derive(Copy, Clone, Debug)]
+pub struct MeshRenderDataVertexUv {
+    pub uv: [f32; 2],
+}
+implement_vertex!(MeshRenderDataVertexUv, uv);
+// what the instanced variants of the scene shaders read per instance, see assets/instancing.glsl
+#[derive(Copy, Clone, Debug)]
+pub struct InstanceData {
+    pub i_model: [[f32; 4]; 4],
+    pub i_normal: [[f32; 3]; 3],
+}
+implement_vertex!(InstanceData, i_model, i_normal);
+
+impl InstanceData {
+    pub fn new(model: Mat4) -> Self {
+        InstanceData { i_model: model.to_cols_array_2d(), i_normal: normal_matrix(model).to_cols_array_2d() }
+    }
+}
+
+// normals need the inverse-transpose to survive non-uniform scale,
+// degenerate scale has no inverse so we fall back to the plain rotation-scale part
+pub fn normal_matrix(model: Mat4) -> Mat3 {
+    let model3 = Mat3::from_mat4(model);
+    if model3.determinant().abs() > f32::EPSILON { model3.inverse().transpose() } else { model3 }
+}
 
 
 pub struct Transform {
@@ -75,13 +210,104 @@ impl Transform {
     }
 }
 
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Projection {
+    Perspective { fov_y: f32 },
+    // height of the view volume in world units, width follows the aspect ratio
+    Orthographic { height: f32 },
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Camera {
+    pub position: Vec3,
+    pub look_at: Vec3,
+    pub up: Vec3,
+    pub projection: Projection,
+    pub near: f32,
+    pub far: f32,
+}
+
+impl Camera {
+    pub fn orthographic(position: Vec3, look_at: Vec3, height: f32) -> Camera {
+        Camera {
+            position,
+            look_at,
+            up: Vec3::Y,
+            projection: Projection::Orthographic { height },
+            near: 0.01,
+            far: 100.,
+        }
+    }
+
+    pub fn view(self: &Self) -> Mat4 {
+        Mat4::look_at_rh(self.position, self.look_at, self.up)
+    }
+
+    pub fn projection(self: &Self, aspect: f32) -> Mat4 {
+        match self.projection {
+            Projection::Perspective { fov_y } => 
+                Mat4::perspective_rh_gl(fov_y, aspect, self.near, self.far),
+            Projection::Orthographic { height } => {
+                let half = Vec2::new(height * aspect, height) / 2.;
+                Mat4::orthographic_rh_gl(-half.x, half.x, -half.y, half.y, self.near, self.far)
+            }
+        }
+    }
+
+    // aspect ratio is taken from the size of the surface we draw to
+    pub fn view_proj(self: &Self, window_size: Vec2) -> Mat4 {
+        self.projection(window_size.x / window_size.y) * self.view()
+    }
+
+    // moves the camera by less than a texel so the world lands on whole texels of a `target_size`
+    // target, returns the moved camera and how far its image has to be shifted back, in texels.
+    // Only orthographic cameras have a fixed texel size in the world, perspective ones stay as they are
+    pub fn snapped_to_texels(self: &Self, window_size: Vec2, target_size: UVec2) -> (Camera, Vec2) {
+        let height = match self.projection {
+            Projection::Orthographic { height } => height,
+            Projection::Perspective { .. } => return (*self, Vec2::ZERO),
+        };
+        let texel = Vec2::new(height * window_size.x / window_size.y, height) / target_size.as_vec2();
+
+        // the camera position in view space, along its right and up axes
+        let view = self.view();
+        let (right, up) = (view.row(0).xyz(), view.row(1).xyz());
+        let position = Vec2::new(right.dot(self.position), up.dot(self.position));
+        let delta = (position / texel).round() * texel - position;
+
+        let shift = right * delta.x + up * delta.y;
+        let snapped = Camera {
+            position: self.position + shift,
+            look_at: self.look_at + shift,
+            ..*self
+        };
+        // the scene moved against the camera, moving the image along with the camera undoes that
+        (snapped, delta / texel)
+    }
+}
+
+// passes the camera matrix along with the user uniforms of the batch
+pub struct Render3dUniforms<'a, U: Uniforms> {
+    pub view_proj: Mat4,
+    pub uniforms: &'a U,
+}
+
+impl<'a, U: Uniforms> Uniforms for Render3dUniforms<'a, U> {
+    fn visit_values<'b, F: FnMut(&str, UniformValue<'b>)>(&'b self, mut f: F) {
+        f("u_view_proj", UniformValue::Mat4(self.view_proj.to_cols_array_2d()));
+        self.uniforms.visit_values(f);
+    }
+}
+
 // pub struct GameObject<'a> {
 //     pub transform: Transform,
 //     pub render_data: &'a MeshRenderData<'a>,
 // }
 
 pub struct ShaderData<'a, U: Uniforms> {
-    pub program: &'a Program,
+    // None draws every batch group with its material's shader,
+    // passes like depth or normals override it for the whole batch
+    pub shader: Option<ShaderHandle>,
     pub uniforms: U,
     pub draw_parameters: DrawParameters<'a>,
 }
@@ -90,86 +316,341 @@ pub struct ShaderData<'a, U: Uniforms> {
 //     data.render(surface);
 // }
 
-#[derive(Debug)]
-pub struct Render3dData {
+// cpu side of a batch, all objects merged into one set of world space streams
+#[derive(Debug, Default)]
+pub struct Render3dBatch {
     pub pos: Vec<MeshRenderDataVertexPos>,
     pub nor: Vec<MeshRenderDataVertexNor>,
+    pub uv: Vec<MeshRenderDataVertexUv>,
     pub ind: Vec<u32>,
-    // render_buffer_uv: Vec<MeshRenderDataVertexNor>,
+    // consecutive index ranges, one draw call each
+    pub groups: Vec<BatchGroup>,
+    // objects drawn with instancing instead of being merged into the streams above
+    pub instances: Vec<InstanceData>,
+    pub instance_groups: Vec<InstanceGroup>,
+}
+
+// one instanced draw of a mesh, over its range of Render3dBatch::instances
+#[derive(Clone, Debug, PartialEq)]
+pub struct InstanceGroup {
+    pub mesh: MeshHandle,
+    pub material: MaterialHandle,
+    pub instances: std::ops::Range<usize>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BatchGroup {
+    pub material: MaterialHandle,
+    pub indices: std::ops::Range<usize>,
+}
+
+impl Render3dBatch {
+    pub fn with_capacity(cap: usize) -> Self {
+        Render3dBatch {
+            pos: Vec::with_capacity(cap),
+            nor: Vec::with_capacity(cap),
+            uv: Vec::with_capacity(cap),
+            ind: Vec::with_capacity(cap),
+            groups: Vec::new(),
+            instances: Vec::new(),
+            instance_groups: Vec::new(),
+        }
+    }
 
+    pub fn clear(&mut self) {
+        Vec::clear(&mut self.pos);
+        Vec::clear(&mut self.nor);
+        Vec::clear(&mut self.uv);
+        Vec::clear(&mut self.ind);
+        Vec::clear(&mut self.groups);
+        Vec::clear(&mut self.instances);
+        Vec::clear(&mut self.instance_groups);
+    }
+
+    // everything from `start` to the end of the indices, skipped when empty
+    pub fn push_group(&mut self, material: MaterialHandle, start: usize) {
+        if start < self.ind.len() {
+            self.groups.push(BatchGroup { material, indices: start..self.ind.len() });
+        }
+    }
+}
+
+// gpu side of a batch
+#[derive(Debug)]
+pub struct Render3dData {
     pub pos_vbo: VertexBuffer<MeshRenderDataVertexPos>, 
     pub nor_vbo: VertexBuffer<MeshRenderDataVertexNor>, 
+    pub uv_vbo: VertexBuffer<MeshRenderDataVertexUv>, 
     pub ibo: IndexBuffer<u32>,
+    pub instance_vbo: VertexBuffer<InstanceData>,
+    // glium doesn't expose the extensions it checks, so this asks it the same way a draw would
+    pub instancing: bool,
+    // every mesh that was drawn instanced, uploaded again when its Storage::generation changes
+    pub meshes: HashMap<MeshHandle, (u64, MeshRenderData)>,
 }
 
 impl Render3dData {
-    pub fn new(display: &Display, cap: usize) -> Self {
+    pub fn new(display: &GlContext, cap: usize) -> Self {
         // let data: Vec<MeshRenderDataVertexPos> = unsafe {std::mem::transmute(mesh.vps)};
+        let instance_vbo = VertexBuffer::empty_dynamic(display, cap).unwrap();
         Render3dData {
-            pos: Vec::with_capacity(cap),
-            nor: Vec::with_capacity(cap),
-            ind: Vec::with_capacity(cap),
-
             pos_vbo: VertexBuffer::empty_dynamic(display, cap).unwrap(),
             nor_vbo: VertexBuffer::empty_dynamic(display, cap).unwrap(),
+            uv_vbo: VertexBuffer::empty_dynamic(display, cap).unwrap(),
             ibo: IndexBuffer::empty_dynamic(display, PrimitiveType::TrianglesList, cap).unwrap(),
+            instancing: instance_vbo.per_instance().is_ok(),
+            instance_vbo,
+            meshes: HashMap::new(),
         }
     }
 
-    pub fn render<S: Surface, U: Uniforms>(&self, surface: &mut S, shader: &Program, uniforms: &U, draw_parameters: &DrawParameters) {
-        surface.draw((&self.pos_vbo, &self.nor_vbo), &self.ibo, &shader, uniforms,
+    pub fn render<S: Surface, U: Uniforms>(&self, surface: &mut S, indices: std::ops::Range<usize>, 
+        shader: &Program, uniforms: &U, draw_parameters: &DrawParameters) 
+    {
+        let ibo = self.ibo.slice(indices).expect("batch group out of range");
+        surface.draw((&self.pos_vbo, &self.nor_vbo, &self.uv_vbo), ibo, &shader, uniforms,
                         draw_parameters).unwrap();
     }
 
-    pub fn send(&mut self) {
-        if self.pos.len() == 0 { return; }
+    pub fn render_instanced<S: Surface, U: Uniforms>(&self, surface: &mut S, group: &InstanceGroup, 
+        shader: &Program, uniforms: &U, draw_parameters: &DrawParameters) 
+    {
+        let (_, mesh) = self.meshes.get(&group.mesh).expect("instanced mesh wasn't sent");
+        let instances = self.instance_vbo.slice(group.instances.clone()).expect("instance group out of range");
+        mesh.render(surface, instances.per_instance().expect("instancing not supported"), shader, uniforms, draw_parameters);
+    }
 
-        if self.pos.len() != self.pos_vbo.len() {
-            self.pos_vbo = VertexBuffer::dynamic(&RenderState::get().display, &self.pos).unwrap();
+    pub fn send(&mut self, display: &GlContext, assets: &Assets, batch: &Render3dBatch) {
+        if !batch.instances.is_empty() {
+            gl_vbo_update(display, &mut self.instance_vbo, &batch.instances);
+            for group in batch.instance_groups.iter() {
+                let generation = assets.meshes.generation(group.mesh);
+                match self.meshes.get(&group.mesh) {
+                    Some((sent, _)) if *sent == generation => (),
+                    _ => {
+                        let mesh = MeshRenderData::new(display, assets.meshes.get(group.mesh));
+                        self.meshes.insert(group.mesh, (generation, mesh));
+                    }
+                }
+            }
+        }
+
+        if batch.pos.len() == 0 { return; }
+
+        if batch.pos.len() != self.pos_vbo.len() {
+            self.pos_vbo = VertexBuffer::dynamic(display, &batch.pos).unwrap();
         } else {
-            self.pos_vbo.write(&self.pos);
+            self.pos_vbo.write(&batch.pos);
         }
 
-        if self.ind.len() != self.ibo.len() {
-            self.ibo = IndexBuffer::dynamic(&RenderState::get().display, PrimitiveType::TrianglesList,  &self.ind).unwrap();
+        gl_vbo_update(display, &mut self.nor_vbo, &batch.nor);
+        gl_vbo_update(display, &mut self.uv_vbo, &batch.uv);
+
+        if batch.ind.len() != self.ibo.len() {
+            self.ibo = IndexBuffer::dynamic(display, PrimitiveType::TrianglesList,  &batch.ind).unwrap();
         } else {
-            self.ibo.write(&self.ind);
+            self.ibo.write(&batch.ind);
         }
     }
 }
 
 
 pub struct Render3dPixelationData {
-    pub render3d_data: Render3dData,
     pub pixel_texture: glium::texture::srgb_texture2d::SrgbTexture2d,
     pub pixel_depth: DepthRenderBuffer,
     pub quad_vbo: VertexBuffer<QuadVertex>,
     pub quad_ibo: IndexBuffer<u32>,
+    pub layout: PixelLayout,
+    // same size as pixel_texture, the post passes ping-pong between them
+    pub post_textures: [SrgbTexture2d; 2],
+    // packed world normals and depth of the low-res target, only drawn for the outline pass
+    pub normal_texture: Texture2d,
+    pub depth_texture: DepthTexture2d,
+    pub outline: OutlineSettings,
+    // uploaded form of the selected palette, see assets/palette.glsl for the layout
+    pub palette: Option<(PaletteHandle, glium::texture::Texture2d)>,
+    pub dither: DitherSettings,
+    // error diffusion patterns have no GPU version and are uploaded as DitherPattern::None
+    pub threshold_texture: (DitherPattern, glium::texture::Texture2d),
+}
+
+impl Render3dPixelationData {
+    // the low-res target is only recreated when the layout asks for a different size
+    pub fn update(&mut self, display: &GlContext, layout: PixelLayout) {
+        if self.layout.texture_size() != layout.texture_size() {
+            let size = layout.texture_size();
+            self.pixel_texture = SrgbTexture2d::empty(display, size.x, size.y).unwrap();
+            self.pixel_depth = DepthRenderBuffer::new(display, DepthFormat::I24, size.x, size.y).unwrap();
+            self.post_textures = [(); 2].map(|_| SrgbTexture2d::empty(display, size.x, size.y).unwrap());
+            self.normal_texture = normal_texture(display, size);
+            self.depth_texture = depth_texture(display, size);
+        }
+        self.layout = layout;
+    }
+
+    pub fn update_palette(&mut self, display: &GlContext, palette: Option<(PaletteHandle, &Palette)>) {
+        let palette = palette.filter(|(_, palette)| !palette.is_empty());
+        if self.palette.as_ref().map(|(handle, _)| *handle) == palette.map(|(handle, _)| handle) {
+            return;
+        }
+        self.palette = palette.map(|(handle, palette)| {
+            let data: Vec<f32> = palette.lab().iter().chain(palette.linear().iter())
+                .flat_map(|c| c.to_array())
+                .collect();
+            let raw = glium::texture::RawImage2d::from_raw_rgb(data, (palette.len() as u32, 2));
+            let texture = glium::texture::Texture2d::with_format(display, raw,
+                glium::texture::UncompressedFloatFormat::F32F32F32,
+                glium::texture::MipmapsOption::NoMipmap).unwrap();
+            (handle, texture)
+        });
+    }
+
+    pub fn update_dither(&mut self, display: &GlContext, dither: DitherSettings) {
+        let pattern = if dither.pattern.is_error_diffusion() { DitherPattern::None } else { dither.pattern };
+        if self.threshold_texture.0 != pattern {
+            self.threshold_texture = (pattern, threshold_texture(display, pattern));
+        }
+        self.dither = dither;
+    }
+}
+
+fn normal_texture(display: &GlContext, size: UVec2) -> Texture2d {
+    Texture2d::empty_with_format(display, glium::texture::UncompressedFloatFormat::U8U8U8U8,
+        glium::texture::MipmapsOption::NoMipmap, size.x, size.y).unwrap()
+}
+
+fn depth_texture(display: &GlContext, size: UVec2) -> DepthTexture2d {
+    DepthTexture2d::empty_with_format(display, DepthFormat::I24,
+        glium::texture::MipmapsOption::NoMipmap, size.x, size.y).unwrap()
+}
+
+fn threshold_texture(display: &GlContext, pattern: DitherPattern) -> glium::texture::Texture2d {
+    let map = pattern.threshold_map().expect("no threshold map for error diffusion");
+    let raw = glium::texture::RawImage2d {
+        data: std::borrow::Cow::Borrowed(map.values.as_slice()),
+        width: map.size,
+        height: map.size,
+        format: glium::texture::ClientFormat::F32,
+    };
+    glium::texture::Texture2d::with_format(display, raw,
+        glium::texture::UncompressedFloatFormat::F32,
+        glium::texture::MipmapsOption::NoMipmap).unwrap()
+}
+
+// how big the low-res target is
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PixelResolution {
+    // virtual resolution independent of the window, e.g. 320x180
+    Fixed(UVec2),
+    // window size divided by this
+    Divisor(u32),
+}
+
+// how the low-res target is put on the window
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PixelScaling {
+    // covers the whole window, pixels may be non-square and uneven
+    Stretch,
+    // largest whole-number scale that fits, centered, the rest is left as bars
+    IntegerLetterbox,
+    // whole-number scale like IntegerLetterbox, but the target grows to cover the window,
+    // so the resolution only sets the pixel size and bars are less than one pixel wide
+    PixelPerfectFit,
+}
+
+impl PixelScaling {
+    pub const ALL: [PixelScaling; 3] = [PixelScaling::Stretch, PixelScaling::IntegerLetterbox, PixelScaling::PixelPerfectFit];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PixelScaling::Stretch => "Stretch",
+            PixelScaling::IntegerLetterbox => "Integer letterbox",
+            PixelScaling::PixelPerfectFit => "Pixel-perfect fit",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PixelationSettings {
+    pub resolution: PixelResolution,
+    pub scaling: PixelScaling,
+    // snap the camera to whole texels and make up for it when upscaling, stops edges from crawling
+    pub snap: bool,
+}
+
+impl Default for PixelationSettings {
+    fn default() -> Self {
+        PixelationSettings { resolution: PixelResolution::Divisor(10), scaling: PixelScaling::Stretch, snap: false }
+    }
+}
+
+// where the low-res target ends up, in window pixels with the origin at the top left
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PixelLayout {
+    pub target_size: UVec2,
+    pub viewport_origin: UVec2,
+    pub viewport_size: UVec2,
+    // extra texels around the target, so shifting it by a sub-texel offset doesn't expose the edge
+    pub margin: u32,
+}
+
+impl PixelLayout {
+    // what actually gets allocated and drawn to
+    pub fn texture_size(&self) -> UVec2 {
+        self.target_size + 2 * self.margin
+    }
+
+    // widens the view to cover the margin, the target itself keeps the framing of `view_proj`
+    pub fn padded_view_proj(&self, view_proj: Mat4) -> Mat4 {
+        let scale = self.target_size.as_vec2() / self.texture_size().as_vec2();
+        Mat4::from_scale(scale.extend(1.)) * view_proj
+    }
+}
+
+impl PixelationSettings {
+    pub fn layout(&self, window_size: UVec2) -> PixelLayout {
+        let window_size = window_size.max(UVec2::ONE);
+        let requested = match self.resolution {
+            PixelResolution::Fixed(size) => size,
+            PixelResolution::Divisor(divisor) => window_size / divisor.max(1),
+        }.clamp(UVec2::ONE, window_size);
+
+        let margin = self.snap as u32;
+        let scale = (window_size / requested).min_element().max(1);
+        let target_size = match self.scaling {
+            PixelScaling::Stretch => return PixelLayout {
+                target_size: requested,
+                viewport_origin: UVec2::ZERO,
+                viewport_size: window_size,
+                margin,
+            },
+            PixelScaling::IntegerLetterbox => requested,
+            PixelScaling::PixelPerfectFit => window_size / scale,
+        };
+        let viewport_size = target_size * scale;
+        PixelLayout {
+            target_size,
+            viewport_origin: (window_size - viewport_size) / 2,
+            viewport_size,
+            margin,
+        }
+    }
 }
 
 
 // granularity is important for readability
 // generics are too constraint
 
-pub fn gl_vbo_update<T>(vbo: &mut VertexBuffer<T>, data: &[T]) 
+pub fn gl_vbo_update<T>(display: &GlContext, vbo: &mut VertexBuffer<T>, data: &[T]) 
 where T: Copy + glium::Vertex 
 {
         if data.len() != vbo.len() {
-            *vbo = VertexBuffer::dynamic(&RenderState::get().display, &data).unwrap();
+            *vbo = VertexBuffer::dynamic(display, &data).unwrap();
         } else {
             vbo.write(&data);
         }
 }
 
-pub fn gl_ibo_update<T>(ibo: &mut IndexBuffer<T>, data: &[T]) 
-where T: glium::index::Index 
-{
-        if data.len() != ibo.len() {
-            *ibo = IndexBuffer::dynamic(&RenderState::get().display, PrimitiveType::TrianglesList,  &data).unwrap();
-        } else {
-            ibo.write(&data);
-        }
-}
 // thread_local! {
 //     static transforms: Vec<Transform> = Vec::new();
 //     static mesh_ids: Vec<usize> = Vec::new();
@@ -178,171 +659,563 @@ where T: glium::index::Index
 //     // static render_buffer: RenderBuffer = Default::default();
 // }
 
-pub fn render3d<S: Surface, U: Uniforms>(
-    target: &mut S,
-    game_objects: &[crate::game::GameObject], 
-    render_data: &mut Render3dData,
-    shader_data: &ShaderData<U>) 
+
+// something render3d can draw a batch with, the glium path or the software rasterizer
+pub trait Renderer {
+    fn size(&self) -> UVec2;
+    fn clear(&mut self, color: Vec4, depth: f32);
+    fn draw_batch(&mut self, batch: &Render3dBatch, view_proj: Mat4);
+    // whether draw_batch can draw Render3dBatch::instance_groups
+    fn instancing(&self) -> bool;
+    // renders the batch's depth from the light into the shadow map the following draws sample
+    fn shadow_pass(&mut self, batch: &Render3dBatch, light_view_proj: Mat4);
+    // runs `draw` on the cleared low-res target, then upscales it onto this one with nearest filtering,
+    // shifted by `texel_offset` low-res texels
+    fn pixelated(&mut self, texel_offset: Vec2, draw: &mut dyn FnMut(&mut dyn Renderer));
+}
+
+pub struct GliumRenderer<'a, S: Surface, U: Uniforms> {
+    pub target: &'a mut S,
+    pub display: &'a GlContext,
+    pub assets: &'a Assets,
+    pub render3d_data: &'a mut Render3dData,
+    // None while drawing into the low-res target itself
+    pub pixelation_data: Option<&'a Render3dPixelationData>,
+    // None for the passes that don't cast shadows
+    pub shadow_map: Option<&'a DepthTexture2d>,
+    pub shader_data: &'a ShaderData<'a, U>,
+}
+
+impl<'a, S: Surface, U: Uniforms> GliumRenderer<'a, S, U> {
+    // the program a batch group is drawn with and the material's parameters on top of `uniforms`,
+    // shaders without an instanced variant are expected to read the instance attributes themselves
+    fn material<'b, V: Uniforms>(&self, material: MaterialHandle, instanced: bool, uniforms: &'b V) 
+        -> (&'a Program, MaterialUniforms<'b, V>) 
+    where 'a: 'b
+    {
+        let assets = self.assets;
+        let material = assets.materials.get(material);
+        let shader = self.shader_data.shader.unwrap_or(material.shader);
+        let shader = assets.shaders.get(shader);
+        let program = match &shader.instanced {
+            Some(program) if instanced => program,
+            _ => shader.program(),
+        };
+        (program, MaterialUniforms { material, program, textures: &assets.textures, uniforms })
+    }
+}
+
+impl<'a, S: Surface, U: Uniforms> Renderer for GliumRenderer<'a, S, U> {
+    fn size(&self) -> UVec2 {
+        self.target.get_dimensions().into()
+    }
+
+    fn clear(&mut self, color: Vec4, depth: f32) {
+        self.target.clear_color_and_depth(color.into(), depth);
+    }
+
+    fn draw_batch(&mut self, batch: &Render3dBatch, view_proj: Mat4) {
+        Render3dData::send(self.render3d_data, self.display, self.assets, batch);
+
+        let uniforms = Render3dUniforms {
+            view_proj,
+            uniforms: &self.shader_data.uniforms,
+        };
+
+        for group in batch.groups.iter() {
+            let (program, uniforms) = self.material(group.material, false, &uniforms);
+            Render3dData::render(self.render3d_data, self.target, 
+                group.indices.clone(),
+                program, 
+                &uniforms, 
+                &self.shader_data.draw_parameters);
+        }
+        for group in batch.instance_groups.iter() {
+            let (program, uniforms) = self.material(group.material, true, &uniforms);
+            Render3dData::render_instanced(self.render3d_data, self.target, 
+                group,
+                program, 
+                &uniforms, 
+                &self.shader_data.draw_parameters);
+        }
+    }
+
+    fn instancing(&self) -> bool {
+        self.render3d_data.instancing
+    }
+
+    fn shadow_pass(&mut self, batch: &Render3dBatch, light_view_proj: Mat4) {
+        let shadow_map = match self.shadow_map {
+            Some(shadow_map) => shadow_map,
+            None => return,
+        };
+        let mut fb = SimpleFrameBuffer::depth_only(self.display, shadow_map).unwrap();
+        fb.clear_depth(1.);
+        // only the depth is kept, the color output goes nowhere
+        let depth_shader = ShaderData {
+            shader: Some(self.assets.shaders.handle("depth").unwrap()),
+            uniforms: EmptyUniforms,
+            draw_parameters: glium::DrawParameters {
+                depth: glium::Depth {
+                    test: glium::draw_parameters::DepthTest::IfLess,
+                    write: true,
+                    .. Default::default()
+                },
+                .. Default::default()
+            },
+        };
+        GliumRenderer {
+            target: &mut fb,
+            display: self.display,
+            assets: self.assets,
+            render3d_data: &mut *self.render3d_data,
+            pixelation_data: None,
+            shadow_map: None,
+            shader_data: &depth_shader,
+        }.draw_batch(batch, light_view_proj);
+    }
+
+    fn pixelated(&mut self, texel_offset: Vec2, draw: &mut dyn FnMut(&mut dyn Renderer)) {
+        let render_data = self.pixelation_data.expect("pixelated() can't be nested");
+
+        let mut fb = glium::framebuffer::SimpleFrameBuffer::with_depth_buffer(
+            self.display, 
+            &render_data.pixel_texture, 
+            &render_data.pixel_depth).unwrap();
+
+        fb.clear_color_and_depth((0., 0., 0., 0.), 1.);
+        debug_assert_eq!(UVec2::from(fb.get_dimensions()), render_data.layout.texture_size());
+
+        draw(&mut GliumRenderer {
+            target: &mut fb,
+            display: self.display,
+            assets: self.assets,
+            render3d_data: &mut *self.render3d_data,
+            pixelation_data: None,
+            shadow_map: None,
+            shader_data: self.shader_data,
+        });
+
+        use glium::uniforms::*;
+        let behavior = glium::uniforms::SamplerBehavior {
+            minify_filter: MinifySamplerFilter::Nearest,
+            magnify_filter: MagnifySamplerFilter::Nearest,
+            ..Default::default()
+        };
+
+        // post passes on the low-res target, each one reads the previous output
+        let mut output = &render_data.pixel_texture;
+        let mut next = 0;
+
+        if render_data.outline.enabled {
+            // the scene again with the normal shader, for the normals and a depth we can sample
+            let mut gbuffer_fb = SimpleFrameBuffer::with_depth_buffer(self.display,
+                &render_data.normal_texture, &render_data.depth_texture).unwrap();
+            gbuffer_fb.clear_color_and_depth((0.5, 0.5, 1., 0.), 1.);
+            let normal_shader = ShaderData {
+                shader: Some(self.assets.shaders.handle("normals").unwrap()),
+                uniforms: EmptyUniforms,
+                draw_parameters: glium::DrawParameters {
+                    depth: glium::Depth {
+                        test: glium::draw_parameters::DepthTest::IfLess,
+                        write: true,
+                        .. Default::default()
+                    },
+                    .. Default::default()
+                },
+            };
+            draw(&mut GliumRenderer {
+                target: &mut gbuffer_fb,
+                display: self.display,
+                assets: self.assets,
+                render3d_data: &mut *self.render3d_data,
+                pixelation_data: None,
+                shadow_map: None,
+                shader_data: &normal_shader,
+            });
+
+            let outline = &render_data.outline;
+            let mut post_fb = SimpleFrameBuffer::new(self.display, &render_data.post_textures[next]).unwrap();
+            let uniforms = uniform! {
+                tex: glium::uniforms::Sampler(output, behavior),
+                u_depth: glium::uniforms::Sampler(&render_data.depth_texture, behavior),
+                u_normal: glium::uniforms::Sampler(&render_data.normal_texture, behavior),
+                u_depth_threshold: outline.depth_threshold,
+                u_normal_threshold: outline.normal_threshold,
+                u_silhouette_color: outline.silhouette_color.to_uniform(),
+                u_crease_color: outline.crease_color.to_uniform(),
+            };
+            let outline_shader = self.assets.shaders.get_by_name("outline").unwrap().program();
+            post_fb.draw(&render_data.quad_vbo, &render_data.quad_ibo, outline_shader,
+                &uniforms, &Default::default()).unwrap();
+            output = &render_data.post_textures[next];
+            next = 1 - next;
+        }
+
+        if let Some((_, palette_texture)) = &render_data.palette {
+            let mut post_fb = SimpleFrameBuffer::new(self.display, &render_data.post_textures[next]).unwrap();
+            let uniforms = uniform! {
+                tex: glium::uniforms::Sampler(output, behavior),
+                u_palette: glium::uniforms::Sampler(palette_texture, behavior),
+                u_threshold: glium::uniforms::Sampler(&render_data.threshold_texture.1, behavior),
+                u_dither_strength: render_data.dither.strength * ORDERED_SPREAD,
+            };
+            let palette_shader = self.assets.shaders.get_by_name("palette").unwrap().program();
+            post_fb.draw(&render_data.quad_vbo, &render_data.quad_ibo, palette_shader,
+                &uniforms, &Default::default()).unwrap();
+            output = &render_data.post_textures[next];
+        }
+
+        let params = glium::DrawParameters {
+            depth: glium::Depth {
+                test: glium::draw_parameters::DepthTest::IfLess,
+                write: true,
+                .. Default::default()
+            },
+            blend: glium::Blend::alpha_blending(),
+            viewport: Some(gl_viewport(render_data.layout, self.size())),
+            .. Default::default()
+        };
+
+        // the margin hangs over the viewport, which clips it away
+        let layout = &render_data.layout;
+        let target_size = layout.target_size.as_vec2();
+        let uniforms = uniform! {
+            tex: glium::uniforms::Sampler(output, behavior),
+            u_scale: (layout.texture_size().as_vec2() / target_size).to_array(),
+            u_offset: (texel_offset * 2. / target_size).to_array(),
+        };
+
+        let quad_shader = self.assets.shaders.get_by_name("quad").unwrap().program();
+        self.target.draw(&render_data.quad_vbo, &render_data.quad_ibo, quad_shader, 
+                    &uniforms,
+                    &params).unwrap();
+    }
+}
+
+// glium counts the viewport from the bottom left
+pub fn gl_viewport(layout: PixelLayout, window_size: UVec2) -> glium::Rect {
+    glium::Rect {
+        left: layout.viewport_origin.x,
+        bottom: window_size.y.saturating_sub(layout.viewport_origin.y + layout.viewport_size.y),
+        width: layout.viewport_size.x,
+        height: layout.viewport_size.y,
+    }
+}
+
+#[derive(Debug)]
+pub enum BatchError {
+    // the batch is indexed with u32, objects past that are left out
+    IndexOverflow { object: String },
+}
+
+impl std::fmt::Display for BatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BatchError::IndexOverflow { object } => 
+                write!(f, "'{}' doesn't fit in the batch, its indices would overflow u32", object),
+        }
+    }
+}
+
+impl std::error::Error for BatchError {}
+
+// meshes used by at least this many objects with the same material are drawn instanced
+pub const MIN_INSTANCES: usize = 8;
+
+// writes the batch, vertices go in world space, view and projection are applied by the renderer
+// Objects are sorted into one group per material so each group is a single draw.
+// With `instancing`, meshes repeated MIN_INSTANCES times or more go into instance groups instead,
+// so only their model matrices are written.
+// Objects that don't fit are left out and reported, the rest of the batch can still be drawn.
+pub fn build_batch(batch: &mut Render3dBatch, assets: &Assets, game_objects: &[crate::game::GameObject], 
+    instancing: bool) -> Vec<BatchError> 
 {
-    
-    // writing batch
-    Vec::clear(&mut render_data.pos);
-    Vec::clear(&mut render_data.ind);
+    batch.clear();
+    // in order of first use, scenes only have a handful so a linear search is fine
+    let mut materials: Vec<MaterialHandle> = Vec::new();
+    let mut counts: HashMap<(MeshHandle, MaterialHandle), usize> = HashMap::new();
+    let mut instanced: Vec<(MeshHandle, MaterialHandle)> = Vec::new();
     for go in game_objects.iter() {
-        let mvp = go.transform.model(); // TODO(mb): upgrade to full mvp matrix
-        render_data.pos.extend(go.mesh.pos.iter()
-            .map(|&p: &Vec3| MeshRenderDataVertexPos {position: (mvp * p.extend(1.)).xyz().into()} ) );
-        
-        let last_ind = if render_data.ind.is_empty() {-1} else {render_data.ind[render_data.ind.len()-1] as i32};
-        render_data.ind.extend(go.mesh.ind.iter()
-            .map(|&i: &u32| (last_ind + 1 + i as i32) as u32 ) );
+        let mesh = match go.mesh {
+            Some(mesh) => mesh,
+            None => continue,
+        };
+        if !materials.contains(&go.material) {
+            materials.push(go.material);
+        }
+        let count = counts.entry((mesh, go.material)).or_default();
+        *count += 1;
+        if instancing && *count == MIN_INSTANCES {
+            instanced.push((mesh, go.material));
+        }
     }
-    // println!("{:?}", render_buffer.pos);
-    // println!("{:?}", render_buffer.ind);
+    let is_instanced = |go: &crate::game::GameObject| match go.mesh {
+        Some(mesh) => instanced.contains(&(mesh, go.material)),
+        None => false,
+    };
 
-    Render3dData::send(render_data);
+    let mut errors = Vec::new();
+    for material in materials {
+        let start = batch.ind.len();
+        for go in game_objects.iter().filter(|go| go.material == material && !is_instanced(go)) {
+            if let Err(e) = build_batch_object(batch, assets, go) {
+                errors.push(e);
+            }
+        }
+        batch.push_group(material, start);
+    }
 
-    Render3dData::render(render_data, target, 
-        &shader_data.program, 
-        &shader_data.uniforms, 
-        &shader_data.draw_parameters);
+    for &(mesh, material) in instanced.iter() {
+        let start = batch.instances.len();
+        batch.instances.extend(game_objects.iter()
+            .filter(|go| go.mesh == Some(mesh) && go.material == material)
+            .map(|go| InstanceData::new(go.transform.model())));
+        batch.instance_groups.push(InstanceGroup { mesh, material, instances: start..batch.instances.len() });
+    }
+    errors
+}
 
+// where the mesh's vertices start in a batch that already has `batch_vertices`,
+// None when the batch would need indices past u32::MAX
+fn index_base(batch_vertices: usize, mesh: &Mesh) -> Option<u32> {
+    let end = u32::try_from(batch_vertices.checked_add(mesh.pos.len())?).ok()?;
+    let base = end - mesh.pos.len() as u32;
+    // indices past the mesh's own vertices mean a broken mesh, but they still mustn't wrap around
+    base.checked_add(mesh.ind.iter().copied().max().unwrap_or(0))?;
+    Some(base)
 }
 
-pub fn render3d_pixelation<S: Surface, U: Uniforms>(
-    target: &mut S,
-    game_objects: &[crate::game::GameObject], 
-    render_data: &mut Render3dPixelationData,
-    shader_data: &ShaderData<U>) 
+fn build_batch_object(batch: &mut Render3dBatch, assets: &Assets, go: &crate::game::GameObject) 
+    -> Result<(), BatchError> 
 {
-    
-    let mut fb = glium::framebuffer::SimpleFrameBuffer::with_depth_buffer(
-        &RenderState::get().display, 
-        &render_data.pixel_texture, 
-        &render_data.pixel_depth).unwrap();
+    let mesh = match go.mesh {
+        Some(handle) => assets.meshes.get(handle),
+        // lights and other objects without anything to draw
+        None => return Ok(()),
+    };
+    // checked before anything is written so the streams stay aligned
+    let base = index_base(batch.pos.len(), mesh)
+        .ok_or_else(|| BatchError::IndexOverflow { object: go.name.clone() })?;
+    let model = go.transform.model();
+    batch.pos.extend(mesh.pos.iter()
+        .map(|&p: &Vec3| MeshRenderDataVertexPos {position: (model * p.extend(1.)).xyz().into()} ) );
+
+    let normal_mat = normal_matrix(model);
+    if mesh.nor.len() == mesh.pos.len() {
+        batch.nor.extend(mesh.nor.iter()
+            .map(|&n: &Vec3| MeshRenderDataVertexNor {normal: (normal_mat * n).normalize_or_zero().into()} ) );
+    } else {
+        // keep the streams the same length, meshes without normals get zeros
+        batch.nor.extend(mesh.pos.iter()
+            .map(|_| MeshRenderDataVertexNor {normal: Vec3::ZERO.into()} ) );
+    }
+    if mesh.has_uv() {
+        batch.uv.extend(mesh.uv.iter().map(|&uv| MeshRenderDataVertexUv {uv: uv.into()} ) );
+    } else {
+        batch.uv.extend(mesh.pos.iter().map(|_| MeshRenderDataVertexUv {uv: [0., 0.]} ) );
+    }
+
+    // mesh indices are relative to its own vertices
+    batch.ind.extend(mesh.ind.iter().map(|&i| base + i));
+    Ok(())
+}
 
-    fb.clear_color_and_depth((0., 0., 0., 0.), 1.);
+// `shadow` is the light's view_proj when there's a shadow pass, see shadow::shadow_view_proj.
+// Returns the objects build_batch had to leave out, everything else is drawn
+pub fn render3d<R: Renderer + ?Sized>(
+    renderer: &mut R,
+    assets: &Assets,
+    game_objects: &[crate::game::GameObject], 
+    view_proj: Mat4,
+    shadow: Option<Mat4>,
+    batch: &mut Render3dBatch) -> Vec<BatchError>
+{
+    let errors = build_batch(batch, assets, game_objects, renderer.instancing());
+    if let Some(light_view_proj) = shadow {
+        renderer.shadow_pass(batch, light_view_proj);
+    }
+    renderer.draw_batch(batch, view_proj);
+    errors
+}
 
-    render3d(&mut fb, game_objects, &mut render_data.render3d_data, shader_data);
+pub fn render3d_pixelation<R: Renderer + ?Sized>(
+    renderer: &mut R,
+    assets: &Assets,
+    game_objects: &[crate::game::GameObject], 
+    view_proj: Mat4,
+    texel_offset: Vec2,
+    shadow: Option<Mat4>,
+    batch: &mut Render3dBatch) -> Vec<BatchError>
+{
+    let errors = build_batch(batch, assets, game_objects, renderer.instancing());
+    if let Some(light_view_proj) = shadow {
+        renderer.shadow_pass(batch, light_view_proj);
+    }
+    let batch = &*batch;
+    renderer.pixelated(texel_offset, &mut |low_res: &mut dyn Renderer| low_res.draw_batch(batch, view_proj));
+    errors
+}
 
-    let params = glium::DrawParameters {
-        depth: glium::Depth {
-            test: glium::draw_parameters::DepthTest::IfLess,
-            write: true,
-            .. Default::default()
-        },
-        blend: glium::Blend::alpha_blending(),
-        .. Default::default()
-    };
-    
-    use glium::uniforms::*;
-    let behavior = glium::uniforms::SamplerBehavior {
-        minify_filter: MinifySamplerFilter::Nearest,
-        magnify_filter: MagnifySamplerFilter::Nearest,
-        ..Default::default()
-    };
-    let uniforms = uniform! {
-        tex: glium::uniforms::Sampler(&render_data.pixel_texture, behavior),
-    };
 
-    target.draw(&render_data.quad_vbo, &render_data.quad_ibo, &Assets::get().shaders[0], 
-                &uniforms,
-                &params).unwrap();
 
+// the GL context we draw with, either a window or an offscreen context for machines without a display
+pub enum GlContext {
+    Window(Display),
+    Headless(Rc<glium::backend::Context>),
 }
 
+impl GlContext {
+    pub fn window(&self) -> Option<&Display> {
+        match self {
+            GlContext::Window(display) => Some(display),
+            GlContext::Headless(_) => None,
+        }
+    }
+}
 
+impl Facade for GlContext {
+    fn get_context(&self) -> &Rc<glium::backend::Context> {
+        match self {
+            GlContext::Window(display) => display.get_context(),
+            GlContext::Headless(context) => context,
+        }
+    }
+}
 
 pub struct RenderState 
 {
 
+    // physical pixels, what the framebuffer has
     pub window_size: Vec2,
-    // static mut display: *mut Display = std::ptr::null_mut();
-    pub display: Display,
-    pub egui_glium: egui_glium::EguiGlium,
+    pub display: GlContext,
+    // only there when we have a window
+    pub egui_glium: Option<egui_glium::EguiGlium>,
+
+    pub batch: Render3dBatch,
+    pub render3d_data: Render3dData,
 
 
     pub render3d_pixelation_data: Render3dPixelationData,
+    // uploaded ToonSettings::ramp
+    pub toon_ramp: Option<(PaletteHandle, Texture2d)>,
+    // sized by ShadowSettings::map_size
+    pub shadow_map: DepthTexture2d,
 
     // _marker: std::marker::PhantomData<RenderBuffer>
 }
 
 impl RenderState {
-    // const unsafe fn uninit() -> Self {
-    //     const_zero::const_zero!(RenderState)
-    // }
-    
-    // pub fn init(value: Self) {
-    //     unsafe {
-    //         render_state = std::alloc::alloc(std::alloc::Layout::new::<Self>()) as *mut Self;
-    //         *render_state = value;
-    //     }
-    // }
-
-    pub fn init(window_size: UVec2, event_loop: &EventLoop<()>) {
-        unsafe {
-            let display = create_display(&event_loop, window_size.into());
-            let egui_glium = egui_glium::EguiGlium::new(&display, &event_loop);
-            let render_buffer = Render3dData::new(&display, 100);
-
-            let pixel_texture_size = window_size / 10;
-            let pixel_texture = glium::texture::srgb_texture2d::SrgbTexture2d::empty(&display, pixel_texture_size.x, pixel_texture_size.y).unwrap();
-            let pixel_depth = glium::framebuffer::DepthRenderBuffer::new(&display, 
-                glium::texture::DepthFormat::I24, pixel_texture_size.x, pixel_texture_size.y).unwrap();
-
-            let vertex1 = QuadVertex { position: [-1.0, -1.0], uv: [0.0, 0.0] };
-            let vertex2 = QuadVertex { position: [ 1.0,  -1.0], uv: [1.0, 0.0] };
-            let vertex3 = QuadVertex { position: [ 1.0, 1.0], uv: [1.0, 1.0] };
-            let vertex4 = QuadVertex { position: [ -1.0, 1.0], uv: [0.0, 1.0] };
-            let quad = vec![vertex1, vertex2, vertex3, vertex4];
-            let quad_indices = [0_u32, 1, 2, 0, 2, 3];
-
-            let quad_vbo = glium::VertexBuffer::new(&display, &quad).unwrap();
-            let quad_ibo = glium::IndexBuffer::new(&display, glium::index::PrimitiveType::TrianglesList, &quad_indices).unwrap();
-
-            // render_state.write(val)._marker = PhantomData;
-
-            render_state = Some(RenderState {
-                window_size: window_size.as_vec2(),
-                display,
-                egui_glium,
-                render3d_pixelation_data: Render3dPixelationData {
-                    render3d_data: render_buffer,
-                    pixel_texture,
-                    pixel_depth,
-                    quad_vbo,
-                    quad_ibo,
-                }
-            });
+    pub fn new(window_size: UVec2, event_loop: &EventLoop<()>) -> Self {
+        let display = create_display(&event_loop, window_size.into());
+        let egui_glium = egui_glium::EguiGlium::new(&display, &event_loop);
+        // the window is created with a logical size, on high-dpi screens the framebuffer is bigger
+        // egui follows the scale factor itself through on_event
+        let physical_size = {
+            let size = display.gl_window().window().inner_size();
+            UVec2::new(size.width, size.height)
+        };
+        Self::with_context(physical_size, GlContext::Window(display), Some(egui_glium))
+    }
+
+    // offscreen context, no window or display server needed, see create_headless_context
+    pub fn headless(window_size: UVec2) -> Result<Self, HeadlessError> {
+        let context = create_headless_context(window_size)?;
+        Ok(Self::with_context(window_size, GlContext::Headless(context), None))
+    }
+
+    fn with_context(window_size: UVec2, display: GlContext, egui_glium: Option<egui_glium::EguiGlium>) -> Self {
+        let render_buffer = Render3dData::new(&display, 100);
+
+        let layout = PixelationSettings::default().layout(window_size);
+        let pixel_texture_size = layout.texture_size();
+        let pixel_texture = glium::texture::srgb_texture2d::SrgbTexture2d::empty(&display, pixel_texture_size.x, pixel_texture_size.y).unwrap();
+        let pixel_depth = glium::framebuffer::DepthRenderBuffer::new(&display, 
+            glium::texture::DepthFormat::I24, pixel_texture_size.x, pixel_texture_size.y).unwrap();
+        let post_textures = [(); 2].map(|_| SrgbTexture2d::empty(&display, pixel_texture_size.x, pixel_texture_size.y).unwrap());
+
+        let vertex1 = QuadVertex { position: [-1.0, -1.0], uv: [0.0, 0.0] };
+        let vertex2 = QuadVertex { position: [ 1.0,  -1.0], uv: [1.0, 0.0] };
+        let vertex3 = QuadVertex { position: [ 1.0, 1.0], uv: [1.0, 1.0] };
+        let vertex4 = QuadVertex { position: [ -1.0, 1.0], uv: [0.0, 1.0] };
+        let quad = vec![vertex1, vertex2, vertex3, vertex4];
+        let quad_indices = [0_u32, 1, 2, 0, 2, 3];
+
+        let quad_vbo = glium::VertexBuffer::new(&display, &quad).unwrap();
+        let quad_ibo = glium::IndexBuffer::new(&display, glium::index::PrimitiveType::TrianglesList, &quad_indices).unwrap();
+
+        RenderState {
+            window_size: window_size.as_vec2(),
+            egui_glium,
+            batch: Render3dBatch::with_capacity(100),
+            render3d_data: render_buffer,
+            render3d_pixelation_data: Render3dPixelationData {
+                pixel_texture,
+                pixel_depth,
+                quad_vbo,
+                quad_ibo,
+                layout,
+                post_textures,
+                normal_texture: normal_texture(&display, pixel_texture_size),
+                depth_texture: depth_texture(&display, pixel_texture_size),
+                outline: OutlineSettings::default(),
+                palette: None,
+                dither: DitherSettings::default(),
+                threshold_texture: (DitherPattern::None, threshold_texture(&display, DitherPattern::None)),
+            },
+            toon_ramp: None,
+            shadow_map: depth_texture(&display, UVec2::splat(ShadowSettings::default().resolution)),
+            // last, the textures above are created from it
+            display,
         }
     }
 
-    // pub fn init(window_size: UVec2, event_loop: &EventLoop<()>) {
-    //     unsafe {
+    pub fn update_shadow_map(&mut self, size: u32) {
+        if self.shadow_map.width() != size {
+            self.shadow_map = depth_texture(&self.display, UVec2::splat(size));
+        }
+    }
 
-    //         render_state.write(val)._marker = PhantomData;
-    //         render_state = std::ptr::Unique::new(std::alloc::alloc(std::alloc::Layout::new::<Self>()) as *mut Self).unwrap();
+    pub fn update_toon_ramp(&mut self, ramp: Option<(PaletteHandle, &Palette)>) {
+        let ramp = ramp.filter(|(_, palette)| !palette.is_empty());
+        if self.toon_ramp.as_ref().map(|(handle, _)| *handle) != ramp.map(|(handle, _)| handle) {
+            self.toon_ramp = ramp.map(|(handle, palette)| (handle, toon::ramp_texture(&self.display, palette)));
+        }
+    }
 
-    //         let display = create_display(&event_loop, window_size.into());
-    //         render_state.as_mut().window_size = window_size.as_vec2();
-    //         render_state.as_mut().egui_glium = egui_glium::EguiGlium::new(&render_state.as_mut().display, &event_loop);
-    //         render_state.as_mut().render_buffer = RenderBuffer::new(&render_state.as_mut().display, 100);
-    //         render_state.as_mut().display = display;
+    // new physical size from WindowEvent::Resized or ScaleFactorChanged.
+    // Projections read window_size every frame and the low-res target follows
+    // the framebuffer size in render_scene, so only the surface itself needs resizing here.
+    pub fn resize(&mut self, size: UVec2) {
+        // minimized windows report zero, keep the last usable size
+        if size.x == 0 || size.y == 0 || size == self.window_size.as_uvec2() {
+            return;
+        }
+        self.window_size = size.as_vec2();
+        if let Some(display) = self.display.window() {
+            display.gl_window().resize(glutin::dpi::PhysicalSize::new(size.x, size.y));
+        }
+    }
 
-    //     }
-    // }
+    pub fn request_redraw(&self) {
+        if let Some(display) = self.display.window() {
+            display.gl_window().window().request_redraw();
+        }
+    }
 
-    pub fn get() -> &'static mut Self {
-        unsafe { render_state.as_mut().unwrap() }
+    // draws into an offscreen target of window size and reads the result back
+    pub fn render_offscreen(&mut self, draw: impl FnOnce(&mut SimpleFrameBuffer, &mut RenderState)) -> RgbaImage {
+        let size = self.window_size.as_uvec2();
+        let color = SrgbTexture2d::empty(&self.display, size.x, size.y).unwrap();
+        let depth = DepthRenderBuffer::new(&self.display, DepthFormat::I24, size.x, size.y).unwrap();
+        {
+            let mut fb = SimpleFrameBuffer::with_depth_buffer(&self.display, &color, &depth).unwrap();
+            draw(&mut fb, self);
+        }
+        RgbaImage::from_gl(color.read())
     }
 }
 
-pub static mut render_state: Option<RenderState> = None;
-// pub static mut render_state: std::mem::MaybeUninit<RenderState> = MaybeUninit::<RenderState>::uninit();
-// pub static mut render_state: std::ptr::Unique<RenderState> = unsafe {
-//     std::ptr::Unique::new_unchecked(std::ptr::NonNull::dangling().as_ptr())  
-// };
-
 fn create_display(event_loop: &glutin::event_loop::EventLoop<()>, window_size: (u32, u32)) -> glium::Display {
     let window_builder = glutin::window::WindowBuilder::new()
         .with_resizable(true)
@@ -368,3 +1241,234 @@ pub struct QuadVertex {
 }
 
 implement_vertex!(QuadVertex, position, uv);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapped_camera_puts_world_on_texel_grid() {
+        let target = UVec2::new(32, 18);
+        let size = Vec2::new(320., 180.);
+        for i in 0..10 {
+            let pan = Vec3::new(0.0123, 0., -0.007) * i as f32;
+            let camera = Camera::orthographic(Vec3::new(1., 1., 1.) + pan, pan, 0.5);
+            let (snapped, offset) = camera.snapped_to_texels(size, target);
+            let to_texels = |camera: &Camera| camera.view_proj(size).project_point3(Vec3::ZERO).truncate() * target.as_vec2() / 2.;
+
+            let texels = to_texels(&snapped);
+            assert!(texels.abs_diff_eq(texels.round(), 1e-3), "{}", texels);
+            assert!(offset.abs().max_element() <= 0.5 + 1e-4, "{}", offset);
+            // shifting the image by the offset puts things back where the unsnapped camera had them
+            assert!((texels + offset).abs_diff_eq(to_texels(&camera), 1e-3));
+        }
+    }
+
+    #[test]
+    fn margin_pads_the_texture_only() {
+        let settings = PixelationSettings { snap: true, ..Default::default() };
+        let layout = settings.layout(UVec2::new(100, 50));
+        assert_eq!(layout.target_size, UVec2::new(10, 5));
+        assert_eq!(layout.texture_size(), UVec2::new(12, 7));
+        // the target's corners keep their place inside the bigger texture
+        let corner = layout.padded_view_proj(Mat4::IDENTITY).project_point3(Vec3::new(1., 1., 0.));
+        assert!(corner.truncate().abs_diff_eq(Vec2::new(10. / 12., 5. / 7.), 1e-6));
+    }
+
+    #[test]
+    fn flat_normals_face_out_of_the_cube() {
+        let mut mesh = crate::game::cube_mesh();
+        mesh.generate_flat_normals();
+        assert_eq!(mesh.pos.len(), 36);
+        assert_eq!(mesh.nor.len(), mesh.pos.len());
+        for (&p, &n) in mesh.pos.iter().zip(mesh.nor.iter()) {
+            // an axis pointing away from the center, and the corner lies on that face
+            assert_eq!(n.abs().max_element(), 1.);
+            assert_eq!(n.abs().min_element(), 0.);
+            assert_eq!(p.dot(n), 1.);
+        }
+    }
+
+    #[test]
+    fn smooth_normals_split_at_the_crease_angle() {
+        // the cube's faces meet at 90 degrees
+        let mut hard = crate::game::cube_mesh();
+        hard.generate_smooth_normals(80f32.to_radians());
+        let mut flat = crate::game::cube_mesh();
+        flat.generate_flat_normals();
+        for (&p, &n) in hard.pos.iter().zip(hard.nor.iter()) {
+            assert!(flat.pos.iter().zip(flat.nor.iter()).any(|(&fp, &fnor)| fp == p && fnor == n), "{} {}", p, n);
+        }
+        // the two triangles of a face share their vertices
+        assert_eq!(hard.pos.len(), 24);
+
+        let mut soft = crate::game::cube_mesh();
+        soft.generate_smooth_normals(100f32.to_radians());
+        for (&p, &n) in soft.pos.iter().zip(soft.nor.iter()) {
+            // every corner blends its three faces, points away from the center along all axes
+            assert_eq!(n.signum(), p.signum(), "{} {}", p, n);
+            assert!(n.abs().min_element() > 0.1, "{} {}", p, n);
+            assert!((n.length() - 1.).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn normal_generation_keeps_uvs() {
+        for smooth in [false, true] {
+            let mut mesh = crate::game::cube_mesh();
+            if smooth {
+                mesh.generate_smooth_normals(std::f32::consts::FRAC_PI_4);
+            } else {
+                mesh.generate_flat_normals();
+            }
+            assert_eq!(mesh.uv.len(), mesh.pos.len());
+            // every corner still has the uv it had on its face
+            let original = crate::game::cube_mesh();
+            for (&old, &new) in original.ind.iter().zip(mesh.ind.iter()) {
+                assert_eq!(mesh.pos[new as usize], original.pos[old as usize]);
+                assert_eq!(mesh.uv[new as usize], original.uv[old as usize]);
+            }
+        }
+    }
+
+    fn object(name: &str, mesh: MeshHandle, material: usize, position: Vec3) -> crate::game::GameObject {
+        crate::game::GameObject {
+            name: name.to_owned(),
+            transform: Transform { position, ..Transform::id() },
+            mesh: Some(mesh),
+            material: Handle::dangling(material),
+            light: None,
+        }
+    }
+
+    fn quad_mesh() -> Mesh {
+        Mesh {
+            pos: vec![Vec3::new(-1., -1., 0.), Vec3::new(1., -1., 0.), Vec3::new(1., 1., 0.), Vec3::new(-1., 1., 0.)],
+            nor: Vec::new(),
+            uv: Vec::new(),
+            ind: vec![0, 1, 2, 0, 2, 3],
+        }
+    }
+
+    #[test]
+    fn batch_offsets_indices_by_vertex_count() {
+        let mut assets = Assets::default();
+        let cube = assets.meshes.add("cube", crate::game::cube_mesh());
+        let quad = assets.meshes.add("quad", quad_mesh());
+        // the cube's index list ends at 20, the quad has to start after all 24 of its vertices
+        assert_eq!(*assets.meshes.get(cube).ind.last().unwrap(), 20);
+        let objects = [object("cube", cube, 0, Vec3::ZERO), object("quad", quad, 0, Vec3::X)];
+
+        let mut batch = Render3dBatch::default();
+        assert!(build_batch(&mut batch, &assets, &objects, true).is_empty());
+        assert_eq!(batch.pos.len(), 28);
+        assert_eq!(batch.nor.len(), 28);
+        assert_eq!(batch.uv.len(), 28);
+        assert_eq!(batch.ind[..36], assets.meshes.get(cube).ind[..]);
+        assert_eq!(batch.ind[36..], [24, 25, 26, 24, 26, 27]);
+        // every index still points at the vertex it did in its own mesh
+        assert_eq!(Vec3::from(batch.pos[batch.ind[38] as usize].position), Vec3::new(2., 1., 0.));
+        assert_eq!(batch.groups, vec![BatchGroup { material: Handle::dangling(0), indices: 0..42 }]);
+    }
+
+    #[test]
+    fn batch_handles_any_index_order() {
+        let mut assets = Assets::default();
+        let quad = assets.meshes.add("quad", quad_mesh());
+        // highest index first and last index in the middle of the vertex range
+        let shuffled = assets.meshes.add("shuffled", Mesh { ind: vec![3, 2, 0, 0, 1, 2], ..quad_mesh() });
+        let objects = [
+            object("shuffled", shuffled, 0, Vec3::ZERO),
+            object("quad", quad, 1, Vec3::ZERO),
+            object("shuffled again", shuffled, 0, Vec3::ZERO),
+        ];
+
+        let mut batch = Render3dBatch::default();
+        assert!(build_batch(&mut batch, &assets, &objects, true).is_empty());
+        // grouped by material in order of first use
+        assert_eq!(batch.ind, vec![
+            3, 2, 0, 0, 1, 2,
+            7, 6, 4, 4, 5, 6,
+            8, 9, 10, 8, 10, 11,
+        ]);
+        assert_eq!(batch.groups, vec![
+            BatchGroup { material: Handle::dangling(0), indices: 0..12 },
+            BatchGroup { material: Handle::dangling(1), indices: 12..18 },
+        ]);
+        assert!(batch.ind.iter().all(|&i| (i as usize) < batch.pos.len()));
+    }
+
+    #[test]
+    fn index_base_rejects_u32_overflow() {
+        let quad = quad_mesh();
+        assert_eq!(index_base(0, &quad), Some(0));
+        assert_eq!(index_base(u32::MAX as usize - 4, &quad), Some(u32::MAX - 4));
+        assert_eq!(index_base(u32::MAX as usize - 3, &quad), None);
+        assert_eq!(index_base(usize::MAX, &quad), None);
+        // out of range indices can't wrap around either
+        let broken = Mesh { ind: vec![0, 1, u32::MAX], ..quad_mesh() };
+        assert_eq!(index_base(1, &broken), None);
+    }
+
+    #[test]
+    fn batch_skips_only_objects_that_overflow() {
+        let mut assets = Assets::default();
+        let quad = assets.meshes.add("quad", quad_mesh());
+        // fits at the start of a batch but not after anything else
+        let broken = assets.meshes.add("broken", Mesh { ind: vec![0, 1, u32::MAX], ..quad_mesh() });
+        let objects = [
+            object("quad", quad, 0, Vec3::ZERO),
+            object("broken", broken, 0, Vec3::ZERO),
+            object("second quad", quad, 0, Vec3::ZERO),
+            object("other material", quad, 1, Vec3::ZERO),
+        ];
+
+        let mut batch = Render3dBatch::default();
+        let errors = build_batch(&mut batch, &assets, &objects, true);
+        assert!(matches!(&errors[..], [BatchError::IndexOverflow { object }] if object == "broken"));
+        assert_eq!(batch.pos.len(), 12);
+        assert_eq!(batch.groups, vec![
+            BatchGroup { material: Handle::dangling(0), indices: 0..12 },
+            BatchGroup { material: Handle::dangling(1), indices: 12..18 },
+        ]);
+    }
+
+    #[test]
+    fn repeated_meshes_are_instanced() {
+        let mut assets = Assets::default();
+        let quad = assets.meshes.add("quad", quad_mesh());
+        let cube = assets.meshes.add("cube", crate::game::cube_mesh());
+        let mut objects: Vec<_> = (0..MIN_INSTANCES)
+            .map(|i| object("prop", quad, 0, Vec3::X * i as f32))
+            .collect();
+        // one short of the threshold, and the same mesh with another material counts separately
+        objects.extend((0..MIN_INSTANCES - 1).map(|_| object("crate", cube, 0, Vec3::ZERO)));
+        objects.push(object("other prop", quad, 1, Vec3::ZERO));
+
+        let mut batch = Render3dBatch::default();
+        assert!(build_batch(&mut batch, &assets, &objects, true).is_empty());
+        assert_eq!(batch.instance_groups, vec![
+            InstanceGroup { mesh: quad, material: Handle::dangling(0), instances: 0..MIN_INSTANCES },
+        ]);
+        assert_eq!(batch.instances[3].i_model, Mat4::from_translation(Vec3::X * 3.).to_cols_array_2d());
+        // the rest is merged as before
+        assert_eq!(batch.pos.len(), 24 * (MIN_INSTANCES - 1) + 4);
+        assert_eq!(batch.groups.len(), 2);
+
+        // renderers without instancing get everything merged
+        assert!(build_batch(&mut batch, &assets, &objects, false).is_empty());
+        assert!(batch.instances.is_empty() && batch.instance_groups.is_empty());
+        assert_eq!(batch.pos.len(), 24 * (MIN_INSTANCES - 1) + 4 * (MIN_INSTANCES + 1));
+    }
+
+    #[test]
+    fn instance_normals_stay_perpendicular() {
+        let model = Transform { scale: Vec3::new(2., 1., 0.5), rotation: Quat::from_rotation_y(0.7), ..Transform::id() }.model();
+        let instance = InstanceData::new(model);
+        let n = Vec3::new(0.3, 0.8, 0.1).normalize();
+        // what the instanced shaders do with the normal, against a direction along the surface
+        let shaded = Mat3::from_cols_array_2d(&instance.i_normal) * n;
+        let tangent = model.transform_vector3(n.any_orthonormal_vector());
+        assert!(shaded.dot(tangent).abs() < 1e-5);
+    }
+}
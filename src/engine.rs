@@ -0,0 +1,160 @@
+use glium::glutin::event_loop::EventLoop;
+use std::rc::Rc;
+
+use glam::*;
+
+use crate::loading::*;
+use crate::draw::*;
+use crate::headless::*;
+use crate::raster::SoftwareRenderer;
+use crate::light::{self, Shading};
+use crate::toon::ToonShader;
+use crate::material::CpuMaterial;
+use crate::shadow;
+use crate::game::{self, GameState};
+
+// everything the game needs, owned by the event loop instead of living in globals
+pub struct Engine {
+    // None for engines that only render through render_software
+    pub render_state: Option<RenderState>,
+    pub assets: Assets,
+    pub game_state: GameState,
+}
+
+impl Engine {
+    pub fn new(window_size: UVec2, event_loop: &EventLoop<()>) -> Self {
+        Self::software().with_render_state(RenderState::new(window_size, event_loop))
+    }
+
+    // renders without a window, see RenderState::headless
+    pub fn headless(window_size: UVec2) -> Result<Self, HeadlessError> {
+        Ok(Self::software().with_render_state(RenderState::headless(window_size)?))
+    }
+
+    // the assets and the scene without a GL context
+    pub fn software() -> Self {
+        let mut assets = Assets::default();
+        let game_state = game::init(&mut assets);
+        Engine { render_state: None, assets, game_state }
+    }
+
+    // what fails to compile or upload is shown with the other load errors
+    fn with_render_state(mut self, render_state: RenderState) -> Self {
+        let errors = self.assets.create_gl_resources(&render_state.display);
+        self.game_state.load_errors.extend(errors.iter().map(|e| e.to_string()));
+        self.render_state = Some(render_state);
+        self
+    }
+
+    // None without a GL context
+    pub fn render_to_image(&mut self) -> Option<RgbaImage> {
+        let Engine { render_state, assets, game_state } = self;
+        let render_state = render_state.as_mut()?;
+        Some(render_state.render_offscreen(|fb, rs| {
+            game::render_scene(fb, rs, assets, game_state);
+        }))
+    }
+
+    // same frame through the software rasterizer, doesn't need a GL context
+    pub fn render_software(&self, size: UVec2) -> RgbaImage {
+        let gs = &self.game_state;
+        let mut renderer = SoftwareRenderer::new(size);
+        renderer.pixelation = gs.pixelation;
+        renderer.palette = gs.palette.map(|handle| self.assets.palettes.get(handle).clone());
+        renderer.dither = gs.dither;
+        renderer.outline = gs.outline;
+        renderer.shadow = gs.shadow;
+        renderer.shadow_map_size = gs.shadow.map_size(gs.is_pixelated.then(|| gs.pixelation.layout(size).texture_size()));
+
+        // same lighting as assets/lit.glsl and assets/toon.glsl
+        let mut lights = light::collect_lights(&gs.game_objects);
+        let shadow = shadow::shadow_view_proj(&mut lights, &gs.shadow, &self.assets, &gs.game_objects);
+        let toon = ToonShader {
+            settings: gs.toon,
+            ramp: gs.toon.ramp.map_or(Vec::new(), |handle| self.assets.palettes.get(handle).linear().to_vec()),
+            view_dir: (gs.camera.position - gs.camera.look_at).normalize_or_zero(),
+        };
+        let materials: std::collections::HashMap<MaterialHandle, CpuMaterial> = self.assets.materials.iter()
+            .map(|(handle, material)| (handle, CpuMaterial::new(&self.assets, material)))
+            .collect();
+        renderer.shade = Rc::new(move |frag| {
+            let material = &materials[&frag.material];
+            let base_color = material.color_at(frag.uv);
+            let color = match material.shading {
+                Shading::Lit => light::shade(&lights, base_color, frag.position, frag.normal, frag.shadow),
+                Shading::Toon => toon.shade(&lights, base_color, frag.position, frag.normal, frag.shadow),
+            };
+            color.extend(1.)
+        });
+        let mut batch = Render3dBatch::default();
+        game::draw_scene(&mut renderer, &self.assets, gs, shadow, &mut batch);
+        renderer.to_image()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: UVec2 = UVec2::new(64, 64);
+
+    #[test]
+    fn software_renders_init_scene() {
+        let engine = Engine::software();
+        assert!(engine.render_state.is_none());
+        assert!(engine.game_state.load_errors.is_empty(), "{:?}", engine.game_state.load_errors);
+
+        let image = engine.render_software(SIZE);
+        assert_eq!(image.size, SIZE);
+        // the camera looks at the cube in the middle, the corners only have the clear color
+        let background = image.pixel(0, 0);
+        assert_eq!(image.pixel(SIZE.x - 1, SIZE.y - 1), background);
+        assert_eq!(background[3], 255);
+        let center = image.pixel(SIZE.x / 2, SIZE.y / 2);
+        assert_ne!(center, background);
+    }
+
+    #[test]
+    fn engines_dont_share_state() {
+        let mut a = Engine::software();
+        let b = Engine::software();
+        let meshes = b.assets.meshes.len();
+
+        a.game_state.game_objects[1].transform.position = Vec3::X;
+        a.game_state.is_pixelated = true;
+        a.assets.add_mesh("extra", Mesh { pos: Vec::new(), nor: Vec::new(), uv: Vec::new(), ind: Vec::new() });
+
+        assert_eq!(b.game_state.game_objects[1].transform.position, Vec3::ZERO);
+        assert!(!b.game_state.is_pixelated);
+        assert_eq!(b.assets.meshes.len(), meshes);
+        assert_ne!(a.render_software(SIZE), b.render_software(SIZE));
+    }
+
+    #[test]
+    fn software_palette_only_uses_palette_colors() {
+        let mut engine = Engine::software();
+        let gs = &mut engine.game_state;
+        gs.is_pixelated = true;
+        gs.palette = Some(engine.assets.palettes.handle("Game Boy").unwrap());
+
+        let image = engine.render_software(SIZE);
+        let palette = engine.assets.palettes.get_by_name("Game Boy").unwrap();
+        // the pixelated target is cleared to transparent, so the window's clear color shows around the objects
+        let background = image.pixel(0, 0);
+        let mut used = Vec::new();
+        for y in 0..SIZE.y {
+            for x in 0..SIZE.x {
+                let pixel = image.pixel(x, y);
+                if pixel == background {
+                    continue;
+                }
+                let [r, g, b, _] = pixel;
+                assert!(palette.colors().contains(&[r, g, b]), "{:?} at {}, {}", [r, g, b], x, y);
+                if !used.contains(&[r, g, b]) {
+                    used.push([r, g, b]);
+                }
+            }
+        }
+        assert!(!used.is_empty());
+    }
+}
@@ -11,39 +11,48 @@ use glam::*;
 
 use crate::loading::*;
 use crate::draw::*;
+use crate::engine::Engine;
+use crate::palette::*;
+use crate::dither::*;
+use crate::outline::*;
+use crate::light::*;
+use crate::toon::*;
+use crate::shadow::*;
+use crate::material::*;
 
 include!("../assets/shaders.rs");
 include!("../assets/cube.rs");
 
 
 pub struct GameObject {
-    pub name: &'static str,
+    pub name: String,
     pub transform: Transform,
-    pub mesh: &'static Mesh,
+    pub mesh: Option<MeshHandle>,
+    // ignored without a mesh
+    pub material: MaterialHandle,
+    pub light: Option<Light>,
 }
 
 pub struct GameState {
     pub game_objects: Vec<GameObject>,
+    pub camera: Camera,
+    pub toon: ToonSettings,
+    pub shadow: ShadowSettings,
     pub t: f32,
     pub is_pixelated: bool,
+    pub pixelation: PixelationSettings,
+    // snaps the pixelated output to these colors
+    pub palette: Option<PaletteHandle>,
+    pub dither: DitherSettings,
+    pub outline: OutlineSettings,
+    // assets that failed to load in init, shown in the side panel
+    pub load_errors: Vec<String>,
 }
-impl GameState {
-    pub fn init(value: Self) {
-        unsafe {
-            game_state = Some(value);
-        }
-    }
-
-    pub fn get() -> &'static mut Self {
-        unsafe { game_state.as_mut().unwrap() }
-    }
-}
-
-static mut game_state: Option<GameState> = None;
 
-pub fn init() {
+// only fills in the CPU side of the assets, see Assets::create_gl_resources
+pub fn init(assets: &mut Assets) -> GameState {
 
-    Assets::get().meshes.push(Mesh {
+    let quad = assets.add_mesh("quad", Mesh {
         pos: vec![
             Vec3 {x: -1.0,  y: -1.0, z: 0.0},
             Vec3 {x:  1.0,  y: -1.0, z: 0.0},
@@ -51,30 +60,119 @@ pub fn init() {
             Vec3 {x:  -1.0, y: 1.0 , z: 0.0},
         ],
         nor: Vec::new(),
+        uv: vec![Vec2::new(0., 1.), Vec2::new(1., 1.), Vec2::new(1., 0.), Vec2::new(0., 0.)],
         ind: vec![0_u32, 1, 2, 0, 2, 3]
     });
-    Assets::get().meshes.push(cube_mesh());
+    let cube = assets.add_mesh("cube", cube_mesh());
+    for (_, mesh) in assets.meshes.iter_mut() {
+        mesh.generate_smooth_normals(std::f32::consts::FRAC_PI_4);
+    }
+
+    assets.add_shader("quad", ShaderSource::from_stages("quad", QUAD_VSH_SRC, QUAD_FSH_SRC));
+    assets.add_shader("triangle", ShaderSource::from_stages("triangle", TRIANGLE_VSH_SRC, TRIANGLE_FSH_SRC));
+    assets.add_shader("wireframe", ShaderSource::from_stages("wireframe", WIREFRAME_VSH_SRC, WIREFRAME_FSH_SRC));
+
+    // the watched copies are looked up at runtime, so the installed binary reloads them too
+    let root = asset_root();
+    assets.add_watched_shader("depth",
+        root.join("depth.glsl"), 
+        include_str!("../assets/depth.glsl")).unwrap();
+    let lit_shader = assets.add_watched_shader("lit",
+        root.join("lit.glsl"), 
+        include_str!("../assets/lit.glsl")).unwrap();
+    let toon_shader = assets.add_watched_shader("toon",
+        root.join("toon.glsl"), 
+        include_str!("../assets/toon.glsl")).unwrap();
+    assets.add_watched_shader("normals",
+        root.join("normals.glsl"), 
+        include_str!("../assets/normals.glsl")).unwrap();
+    assets.add_watched_shader("outline",
+        root.join("outline.glsl"), 
+        include_str!("../assets/outline.glsl")).unwrap();
+    assets.add_watched_shader("palette",
+        root.join("palette.glsl"), 
+        include_str!("../assets/palette.glsl")).unwrap();
+
+    let mut load_errors = Vec::new();
+
+    let crate_path = root.join("textures/crate.png");
+    let crate_texture = match assets.load_texture(&crate_path) {
+        Ok(texture) => Some(texture),
+        Err(e) => {
+            load_errors.push(format!("{}: {}", crate_path.display(), e));
+            None
+        }
+    };
+    let default_material = assets.materials.add("default", Material::standard(lit_shader, Vec3::splat(0.8), None));
+    let crate_material = assets.materials.add("crate", Material::standard(lit_shader, Vec3::ONE, crate_texture));
+    assets.materials.add("toon", Material::standard(toon_shader, Vec3::splat(0.8), None));
+
+    for (name, palette) in builtin_palettes() {
+        assets.palettes.add(name, palette);
+    }
+    load_errors.extend(assets.load_palette_dir(root.join("palettes"))
+        .iter()
+        .map(|e| e.to_string()));
 
-    GameState::init(GameState {
+    let mut gs = GameState {
         game_objects: vec![
             GameObject {
-                name: "test",
+                name: "test".to_owned(),
                 transform: Transform::id(),
-                mesh: &Assets::get().meshes[0],
+                mesh: Some(quad),
+                material: default_material,
+                light: None,
             },
             GameObject {
-                name: "cube",
+                name: "cube".to_owned(),
                 transform: Transform::id(),
-                mesh: &Assets::get().meshes[1],
+                mesh: Some(cube),
+                material: crate_material,
+                light: None,
+            },
+            GameObject {
+                name: "ambient".to_owned(),
+                transform: Transform::id(),
+                mesh: None,
+                material: default_material,
+                light: Some(Light::ambient(Vec3::new(0.4, 0.45, 0.6), 0.3)),
+            },
+            GameObject {
+                name: "sun".to_owned(),
+                transform: Transform {
+                    // shining down and away from the camera
+                    rotation: Quat::from_rotation_arc(-Vec3::Z, Vec3::new(-0.4, -1., -0.6).normalize()),
+                    ..Transform::id()
+                },
+                mesh: None,
+                material: default_material,
+                light: Some(Light::directional(Vec3::new(1., 0.95, 0.85), 1.)),
+            },
+            GameObject {
+                name: "lamp".to_owned(),
+                transform: Transform {
+                    position: Vec3::new(0.2, 0.15, 0.1),
+                    ..Transform::id()
+                },
+                mesh: None,
+                material: default_material,
+                light: Some(Light::point(Vec3::new(1., 0.6, 0.3), 1.5, 0.5)),
             },
         ],
+        // isometric-style view, the usual setup for the pixelated look
+        camera: Camera::orthographic(Vec3::new(1., 1., 1.), Vec3::ZERO, 0.5),
+        toon: ToonSettings::default(),
+        shadow: ShadowSettings::default(),
         t: 0.,
         is_pixelated: false,
-    });
-    GameState::get().game_objects[0].transform.scale = Vec3 {x: 0.0, y: 0.1, z: 0.1};
-    GameState::get().game_objects[1].transform.scale = Vec3 {x: 0.1, y: 0.1, z: 0.1};
-
-    let display = &RenderState::get().display;
+        pixelation: PixelationSettings::default(),
+        palette: None,
+        dither: DitherSettings::default(),
+        outline: OutlineSettings::default(),
+        load_errors,
+    };
+    gs.game_objects[0].transform.scale = Vec3 {x: 0.0, y: 0.1, z: 0.1};
+    gs.game_objects[1].transform.scale = Vec3 {x: 0.1, y: 0.1, z: 0.1};
 
 
     // #[derive(Copy, Clone)]
@@ -104,43 +202,124 @@ pub fn init() {
     // let quad_shape_vbo = glium::VertexBuffer::new(display, &quad).unwrap();
     // let quad_ibo = glium::IndexBuffer::new(display, glium::index::PrimitiveType::TrianglesList, &quad_indices).unwrap();
 
-
-    let quad_program = glium::Program::from_source(display, QUAD_VSH_SRC, QUAD_FSH_SRC, None).unwrap();
-    Assets::get().shaders.push(quad_program);
-
-    let triangle_program = glium::Program::from_source(display, TRIANGLE_VSH_SRC, TRIANGLE_FSH_SRC, None).unwrap();
-    Assets::get().shaders.push(triangle_program);
-
-    let triangle_wf_program = glium::Program::from_source(display, WIREFRAME_VSH_SRC, WIREFRAME_FSH_SRC, None).unwrap();
-    Assets::get().shaders.push(triangle_wf_program);
-
-    let diffuse_program = glium::Program::from_source(display, DIFFUSE_VSH_SRC, DIFFUSE_FSH_SRC, None).unwrap();
-    Assets::get().shaders.push(diffuse_program);
+    gs
 }
 
-pub fn update(dt: f32) {
-    let gs = GameState::get();
+pub fn update(engine: &mut Engine, dt: f32) {
+    let Engine { render_state: rs, assets, game_state: gs } = engine;
+
+    assets.reload_shaders(rs.as_ref().map(|rs| &rs.display));
 
     let cube_rot = &mut gs.game_objects[1].transform.rotation;
     *cube_rot = Quat::from_axis_angle(Vec3::Y, dt) * (*cube_rot);
 }
 
 
-pub fn render(dt: f32, control_flow: &mut ControlFlow) {
-    let rs = RenderState::get();
-    let gs = GameState::get();
-    let egui_glium = &mut rs.egui_glium;
-    let display = &rs.display;
+pub fn render(engine: &mut Engine, dt: f32, control_flow: &mut ControlFlow) {
+    let Engine { render_state: rs, assets, game_state: gs } = engine;
+
+    // headless engines have nothing to present, they render through Engine::render_to_image
+    let rs = match rs {
+        Some(rs) => rs,
+        None => return,
+    };
+    let window = match rs.display.window() {
+        Some(window) => window,
+        None => return,
+    };
+    let mut target = window.draw();
+
+    let batch_errors = render_scene(&mut target, rs, assets, gs);
+
+    let egui_glium = rs.egui_glium.as_mut().unwrap();
+    let display = rs.display.window().unwrap();
+    let repaint_after = egui_glium.run(&display, |egui_ctx| {
+        egui::SidePanel::left("my_side_panel").show(egui_ctx, |ui| {
+            ui.heading("Help me!");
+            ui.label(format!("{}", 1./dt));
+            if ui.button("Quit").clicked() {
+                *control_flow = ControlFlow::Exit;
+            }
+            for error in gs.load_errors.iter() {
+                ui.colored_label(egui::Color32::RED, error);
+            }
+            for error in batch_errors.iter() {
+                ui.colored_label(egui::Color32::RED, error.to_string());
+            }
+            for watch in assets.shader_watches.iter() {
+                if let Some(error) = &watch.error {
+                    ui.colored_label(egui::Color32::RED, error);
+                }
+            }
+            ui.add(egui::Checkbox::new(&mut gs.is_pixelated, "Pixel?"));
+            if gs.is_pixelated {
+                gui_pixelation(ui, &mut gs.pixelation);
+                egui::CollapsingHeader::new("Outline")
+                    .show(ui, |ui| {
+                        gui_outline(ui, &mut gs.outline);
+                    });
+                gui_palette(ui, &assets.palettes, &mut gs.palette);
+                if gs.palette.is_some() {
+                    gui_dither(ui, &mut gs.dither);
+                }
+            }
+            ui.add(egui::Slider::new(&mut gs.t, 0.0..=1.0));
+
+            egui::CollapsingHeader::new("Shadows")
+                .show(ui, |ui| {
+                    gui_shadow(ui, &mut gs.shadow);
+                });
+            egui::CollapsingHeader::new("Toon")
+                .show(ui, |ui| {
+                    gui_toon(ui, &assets.palettes, &mut gs.toon);
+                });
+
+            egui::CollapsingHeader::new("Materials")
+                .show(ui, |ui| {
+                    let handles: Vec<MaterialHandle> = assets.materials.iter().map(|(handle, _)| handle).collect();
+                    for handle in handles {
+                        egui::CollapsingHeader::new(assets.materials.name(handle).to_owned())
+                            .show(ui, |ui| {
+                                gui_material(ui, &assets.shaders, &assets.textures, assets.materials.get_mut(handle));
+                            });
+                    }
+                });
+
+            egui::CollapsingHeader::new("Camera")
+                .show(ui, |ui| {
+                    gui_camera(ui, &mut gs.camera);
+                });
+
+            ui.add(egui::Label::new("Game Objects: "));
+            for go in gs.game_objects.iter_mut() {
+                egui::CollapsingHeader::new(&go.name)
+                    .show(ui, |ui| {
+                        gui_transform(ui, &mut go.transform, -1.0..=1.0);
+                        if go.mesh.is_some() {
+                            gui_material_select(ui, &assets.materials, &mut go.material);
+                        }
+                        gui_light(ui, &mut go.light);
+                    });
+            }
+        });
+    });
 
-    let mut target = display.draw();
+    egui_glium.paint(&display, &mut target);
+
+    // draw things on top of egui here
 
+    target.finish().unwrap();
+
+}
 
+// draws the game objects, shared by the window and the offscreen path,
+// returns the objects that couldn't be batched
+pub fn render_scene<S: Surface>(target: &mut S, rs: &mut RenderState, assets: &Assets, gs: &GameState) -> Vec<BatchError> {
     // let color = egui::Rgba::from_rgb(0.1, 0.3, 0.2);
     // target.clear_color(color[0], color[1], color[2], color[3]);
 
     // draw things behind egui here
     // target.clear_color(70./256., 102./256., 101./256., 1.0);
-    target.clear_color_and_depth((70./256., 102./256., 101./256., 1.0), 1.0);
     // use glium::uniforms::*;
     // let behavior = glium::uniforms::SamplerBehavior {
     //     minify_filter: MinifySamplerFilter::Nearest,
@@ -164,54 +343,66 @@ pub fn render(dt: f32, control_flow: &mut ControlFlow) {
         .. Default::default()
     };
 
-    if !gs.is_pixelated {
-        render3d(&mut target, gs.game_objects.as_slice(), &mut rs.render3d_pixelation_data.render3d_data, &ShaderData {
-            program: &Assets::get().shaders[3], 
-            uniforms: EmptyUniforms, 
-            draw_parameters: params.clone(),
-        });
-        // rs.render_buffer.render(&mut target, &Assets::get().shaders[3], 
-        // &EmptyUniforms, &params);
-    } else {
-        render3d_pixelation(&mut target, gs.game_objects.as_slice(), &mut rs.render3d_pixelation_data, &ShaderData {
-            program: &Assets::get().shaders[3], 
-            uniforms: EmptyUniforms, 
-            draw_parameters: params.clone(),
-        });
-        // rs.render_buffer.render(&mut fb, &Assets::get().shaders[3], 
-        // &EmptyUniforms, &params);
-
-
-    } 
-
-
-
-    let repaint_after = egui_glium.run(&display, |egui_ctx| {
-        egui::SidePanel::left("my_side_panel").show(egui_ctx, |ui| {
-            ui.heading("Help me!");
-            ui.label(format!("{}", 1./dt));
-            if ui.button("Quit").clicked() {
-                *control_flow = ControlFlow::Exit;
-            }
-            ui.add(egui::Checkbox::new(&mut gs.is_pixelated, "Pixel?"));
-            ui.add(egui::Slider::new(&mut gs.t, 0.0..=1.0));
-
-            ui.add(egui::Label::new("Game Objects: "));
-            for go in gs.game_objects.iter_mut() {
-                egui::CollapsingHeader::new(go.name)
-                    .show(ui, |ui| {
-                        gui_transform(ui, &mut go.transform, -1.0..=1.0);
-                    });
-            }
-        });
-    });
-
-    egui_glium.paint(&display, &mut target);
-
-    // draw things on top of egui here
+    let layout = gs.pixelation.layout(target.get_dimensions().into());
+    rs.render3d_pixelation_data.update(&rs.display, layout);
+    rs.render3d_pixelation_data.update_palette(&rs.display, 
+        gs.palette.map(|handle| (handle, assets.palettes.get(handle))));
+    rs.render3d_pixelation_data.update_dither(&rs.display, gs.dither);
+    rs.render3d_pixelation_data.outline = gs.outline;
+    rs.update_toon_ramp(gs.toon.ramp.map(|handle| (handle, assets.palettes.get(handle))));
+
+    rs.update_shadow_map(gs.shadow.map_size(gs.is_pixelated.then(|| layout.texture_size())));
+
+    let mut lights = collect_lights(&gs.game_objects);
+    let shadow = shadow_view_proj(&mut lights, &gs.shadow, assets, &gs.game_objects);
+    let shader_data = ShaderData {
+        shader: None,
+        uniforms: ShadowUniforms {
+            view_proj: shadow,
+            map: &rs.shadow_map,
+            settings: &gs.shadow,
+            uniforms: ToonUniforms {
+                settings: &gs.toon,
+                ramp: rs.toon_ramp.as_ref().map(|(_, texture)| texture),
+                view_dir: gs.camera.position - gs.camera.look_at,
+                uniforms: LightUniforms { lights: &lights },
+            },
+        }, 
+        draw_parameters: params,
+    };
+    let mut renderer = GliumRenderer {
+        target,
+        display: &rs.display,
+        assets,
+        render3d_data: &mut rs.render3d_data,
+        pixelation_data: Some(&rs.render3d_pixelation_data),
+        shadow_map: Some(&rs.shadow_map),
+        shader_data: &shader_data,
+    };
+    draw_scene(&mut renderer, assets, gs, shadow, &mut rs.batch)
+}
 
-    target.finish().unwrap();
+// the backend independent part of a frame, `shadow` from shadow::shadow_view_proj
+pub fn draw_scene<R: Renderer + ?Sized>(renderer: &mut R, assets: &Assets, gs: &GameState, 
+    shadow: Option<Mat4>, batch: &mut Render3dBatch) -> Vec<BatchError>
+{
+    renderer.clear(Vec4::new(70./256., 102./256., 101./256., 1.0), 1.0);
 
+    if !gs.is_pixelated {
+        let view_proj = gs.camera.view_proj(renderer.size().as_vec2());
+        render3d(renderer, assets, gs.game_objects.as_slice(), view_proj, shadow, batch)
+    } else {
+        // the camera aspect follows what ends up on screen, a stretched target keeps the window's
+        let layout = gs.pixelation.layout(renderer.size());
+        let aspect_size = layout.viewport_size.as_vec2();
+        let (camera, texel_offset) = if gs.pixelation.snap {
+            gs.camera.snapped_to_texels(aspect_size, layout.target_size)
+        } else {
+            (gs.camera, Vec2::ZERO)
+        };
+        let view_proj = layout.padded_view_proj(camera.view_proj(aspect_size));
+        render3d_pixelation(renderer, assets, gs.game_objects.as_slice(), view_proj, texel_offset, shadow, batch)
+    }
 }
 
 // pub fn _render(dt: f32, control_flow: &ControlFlow) {
@@ -293,12 +484,6 @@ fn gui_vec3(ui: &mut Ui, v: &mut Vec3, range: RangeInclusive<f32>)  {
     ui.add(egui::Slider::new(&mut v.y, range.clone()));
     ui.add(egui::Slider::new(&mut v.z, range.clone()));
 }
-fn gui_vec4(ui: &mut Ui, v: &mut Vec4, range: RangeInclusive<f32>)  {
-    ui.add(egui::Slider::new(&mut v.x, range.clone()));
-    ui.add(egui::Slider::new(&mut v.y, range.clone()));
-    ui.add(egui::Slider::new(&mut v.z, range.clone()));
-    ui.add(egui::Slider::new(&mut v.w, range.clone()));
-}
 fn gui_quat(ui: &mut Ui, q: &mut Quat, range: RangeInclusive<f32>)  {
     ui.add(egui::Slider::new(&mut q.x, range.clone()));
     ui.add(egui::Slider::new(&mut q.y, range.clone()));
@@ -306,6 +491,115 @@ fn gui_quat(ui: &mut Ui, q: &mut Quat, range: RangeInclusive<f32>)  {
     ui.add(egui::Slider::new(&mut q.w, range.clone()));
 }
 
+fn gui_camera(ui: &mut Ui, camera: &mut Camera) {
+    let mut is_ortho = matches!(camera.projection, Projection::Orthographic { .. });
+    if ui.add(egui::Checkbox::new(&mut is_ortho, "Orthographic")).changed() {
+        camera.projection = if is_ortho {
+            Projection::Orthographic { height: 0.5 }
+        } else {
+            Projection::Perspective { fov_y: std::f32::consts::FRAC_PI_4 }
+        };
+    }
+    match &mut camera.projection {
+        Projection::Perspective { fov_y } => {
+            ui.add(egui::Slider::new(fov_y, 0.1..=3.0).text("fov"));
+        }
+        Projection::Orthographic { height } => {
+            ui.add(egui::Slider::new(height, 0.01..=10.0).text("height"));
+        }
+    }
+    ui.add(egui::Slider::new(&mut camera.near, 0.001..=1.0).text("near"));
+    ui.add(egui::Slider::new(&mut camera.far, 1.0..=1000.0).text("far"));
+    ui.add(egui::Label::new("position"));
+    gui_vec3(ui, &mut camera.position, -10.0..=10.0);
+    ui.add(egui::Label::new("look at"));
+    gui_vec3(ui, &mut camera.look_at, -10.0..=10.0);
+}
+
+fn gui_pixelation(ui: &mut Ui, settings: &mut PixelationSettings) {
+    let mut is_fixed = matches!(settings.resolution, PixelResolution::Fixed(_));
+    if ui.add(egui::Checkbox::new(&mut is_fixed, "Fixed resolution")).changed() {
+        settings.resolution = if is_fixed {
+            PixelResolution::Fixed(UVec2::new(320, 180))
+        } else {
+            PixelResolution::Divisor(10)
+        };
+    }
+    match &mut settings.resolution {
+        PixelResolution::Fixed(size) => {
+            ui.horizontal(|ui| {
+                ui.add(egui::DragValue::new(&mut size.x).clamp_range(1..=4096));
+                ui.label("x");
+                ui.add(egui::DragValue::new(&mut size.y).clamp_range(1..=4096));
+            });
+        }
+        PixelResolution::Divisor(divisor) => {
+            ui.add(egui::Slider::new(divisor, 1..=32).text("divisor"));
+        }
+    }
+    ui.add(egui::Checkbox::new(&mut settings.snap, "snap camera to texels"));
+    egui::ComboBox::from_label("scaling")
+        .selected_text(settings.scaling.name())
+        .show_ui(ui, |ui| {
+            for scaling in PixelScaling::ALL {
+                ui.selectable_value(&mut settings.scaling, scaling, scaling.name());
+            }
+        });
+}
+
+fn gui_palette(ui: &mut Ui, palettes: &Storage<Palette>, selected: &mut Option<PaletteHandle>) {
+    let selected_name = selected.map_or("None", |handle| palettes.name(handle));
+    egui::ComboBox::from_label("palette")
+        .selected_text(selected_name)
+        .show_ui(ui, |ui| {
+            ui.selectable_value(selected, None, "None");
+            for (handle, _) in palettes.iter() {
+                ui.selectable_value(selected, Some(handle), palettes.name(handle));
+            }
+        });
+}
+
+fn gui_dither(ui: &mut Ui, dither: &mut DitherSettings) {
+    egui::ComboBox::from_label("dither")
+        .selected_text(dither.pattern.name())
+        .show_ui(ui, |ui| {
+            // the window draws through the GPU palette pass, which only has the ordered patterns
+            for pattern in DitherPattern::ALL.into_iter().filter(|pattern| !pattern.is_error_diffusion()) {
+                ui.selectable_value(&mut dither.pattern, pattern, pattern.name());
+            }
+        });
+    ui.add(egui::Slider::new(&mut dither.strength, 0.0..=1.0).text("strength"));
+}
+
+fn gui_outline(ui: &mut Ui, outline: &mut OutlineSettings) {
+    ui.add(egui::Checkbox::new(&mut outline.enabled, "enabled"));
+    ui.add(egui::Slider::new(&mut outline.depth_threshold, 0.00001..=0.1).logarithmic(true).text("depth threshold"));
+    ui.add(egui::Slider::new(&mut outline.normal_threshold, 0.0..=std::f32::consts::PI).text("crease angle"));
+    ui.add(egui::Label::new("silhouette"));
+    gui_outline_color(ui, &mut outline.silhouette_color);
+    ui.add(egui::Label::new("crease"));
+    gui_outline_color(ui, &mut outline.crease_color);
+}
+
+fn gui_outline_color(ui: &mut Ui, color: &mut OutlineColor) {
+    ui.horizontal(|ui| {
+        let mut is_ink = matches!(color, OutlineColor::Ink(_));
+        if ui.add(egui::Checkbox::new(&mut is_ink, "ink")).changed() {
+            *color = if is_ink { OutlineColor::Ink(Vec3::ZERO) } else { OutlineColor::Darken(0.5) };
+        }
+        match color {
+            OutlineColor::Darken(factor) => {
+                ui.add(egui::Slider::new(factor, 0.0..=1.0).text("darken"));
+            }
+            OutlineColor::Ink(ink) => {
+                let mut rgb = ink.to_array();
+                ui.color_edit_button_rgb(&mut rgb);
+                *ink = Vec3::from(rgb);
+            }
+        }
+    });
+}
+
 fn gui_transform(ui: &mut Ui, t: &mut Transform, range: RangeInclusive<f32>) {
     ui.add(egui::Label::new("position"));
     gui_vec3(ui, &mut t.position, range.clone());
@@ -314,3 +608,150 @@ fn gui_transform(ui: &mut Ui, t: &mut Transform, range: RangeInclusive<f32>) {
     ui.add(egui::Label::new("scale"));
     gui_vec3(ui, &mut t.scale, range.clone());
 }
+
+fn gui_material_select(ui: &mut Ui, materials: &Storage<Material>, material: &mut MaterialHandle) {
+    egui::ComboBox::from_label("material")
+        .selected_text(materials.name(*material))
+        .show_ui(ui, |ui| {
+            for (handle, _) in materials.iter() {
+                ui.selectable_value(material, handle, materials.name(handle));
+            }
+        });
+}
+
+fn gui_material(ui: &mut Ui, shaders: &Storage<Shader>, textures: &Storage<Texture>, material: &mut Material) {
+    let shading = Shading::from_shader_name(shaders.name(material.shader));
+    egui::ComboBox::from_label("shading")
+        .selected_text(shading.name())
+        .show_ui(ui, |ui| {
+            for s in Shading::ALL {
+                if ui.selectable_label(s == shading, s.name()).clicked() {
+                    if let Ok(shader) = shaders.handle(s.shader_name()) {
+                        material.shader = shader;
+                    }
+                }
+            }
+        });
+    // a shader that failed to compile has nothing to check against, the load errors show why
+    if let Some(program) = &shaders.get(material.shader).program {
+        for name in material.mismatches(program, textures) {
+            ui.colored_label(egui::Color32::RED, format!("{} doesn't match the type of u_{} in the shader and isn't set", name, name));
+        }
+    }
+    for (name, value) in material.params.iter_mut() {
+        match value {
+            MaterialValue::Color(color) => {
+                ui.horizontal(|ui| {
+                    let mut rgb = color.to_array();
+                    ui.color_edit_button_rgb(&mut rgb);
+                    *color = Vec3::from(rgb);
+                    ui.label(name.as_str());
+                });
+            }
+            MaterialValue::Float(v) => {
+                ui.add(egui::DragValue::new(v).speed(0.01).prefix(format!("{}: ", name)));
+            }
+            MaterialValue::Texture(texture, sampler) => {
+                let texture_name = texture.map_or("None", |handle| textures.name(handle));
+                egui::ComboBox::from_label(name.as_str())
+                    .selected_text(texture_name)
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(texture, None, "None");
+                        for (handle, _) in textures.iter() {
+                            ui.selectable_value(texture, Some(handle), textures.name(handle));
+                        }
+                    });
+                if texture.is_some() {
+                    gui_sampler(ui, name, sampler);
+                }
+            }
+        }
+    }
+}
+
+fn gui_sampler(ui: &mut Ui, name: &str, sampler: &mut Sampler) {
+    egui::ComboBox::from_label(format!("{} filter", name))
+        .selected_text(sampler.filter.name())
+        .show_ui(ui, |ui| {
+            for filter in TextureFilter::ALL {
+                ui.selectable_value(&mut sampler.filter, filter, filter.name());
+            }
+        });
+    egui::ComboBox::from_label(format!("{} wrap", name))
+        .selected_text(sampler.wrap.name())
+        .show_ui(ui, |ui| {
+            for wrap in TextureWrap::ALL {
+                ui.selectable_value(&mut sampler.wrap, wrap, wrap.name());
+            }
+        });
+}
+
+fn gui_shadow(ui: &mut Ui, shadow: &mut ShadowSettings) {
+    ui.add(egui::Checkbox::new(&mut shadow.enabled, "enabled"));
+    ui.add(egui::Checkbox::new(&mut shadow.hard_pixel, "hard pixel shadows"));
+    if !shadow.hard_pixel {
+        ui.add(egui::Slider::new(&mut shadow.resolution, 16..=4096).logarithmic(true).text("resolution"));
+        ui.add(egui::Checkbox::new(&mut shadow.pcf, "PCF"));
+    } else {
+        ui.label("resolution follows the low-res target while pixelated");
+    }
+    ui.add(egui::Slider::new(&mut shadow.bias, 0.0..=0.05).text("bias"));
+}
+
+fn gui_toon(ui: &mut Ui, palettes: &Storage<Palette>, toon: &mut ToonSettings) {
+    let ramp_name = toon.ramp.map_or("None", |handle| palettes.name(handle));
+    egui::ComboBox::from_label("ramp")
+        .selected_text(ramp_name)
+        .show_ui(ui, |ui| {
+            ui.selectable_value(&mut toon.ramp, None, "None");
+            for (handle, _) in palettes.iter() {
+                ui.selectable_value(&mut toon.ramp, Some(handle), palettes.name(handle));
+            }
+        });
+    if toon.ramp.is_none() {
+        ui.add(egui::Slider::new(&mut toon.bands, 1..=8).text("bands"));
+    }
+    ui.horizontal(|ui| {
+        let mut rgb = toon.rim_color.to_array();
+        ui.color_edit_button_rgb(&mut rgb);
+        toon.rim_color = Vec3::from(rgb);
+        ui.add(egui::Slider::new(&mut toon.rim_strength, 0.0..=2.0).text("rim"));
+    });
+    ui.add(egui::Slider::new(&mut toon.rim_width, -1.0..=1.0).text("rim width"));
+}
+
+fn gui_light(ui: &mut Ui, light: &mut Option<Light>) {
+    let l = match light {
+        Some(l) => l,
+        None => {
+            if ui.button("add light").clicked() {
+                *light = Some(Light::point(Vec3::ONE, 1., 1.));
+            }
+            return;
+        }
+    };
+    let kinds = [LightKind::Ambient, LightKind::Directional, LightKind::Point { range: 1. }];
+    egui::ComboBox::from_label("light")
+        .selected_text(l.kind.name())
+        .show_ui(ui, |ui| {
+            for kind in kinds {
+                // keep the range when the kind doesn't change
+                let selected = l.kind.name() == kind.name();
+                if ui.selectable_label(selected, kind.name()).clicked() && !selected {
+                    l.kind = kind;
+                }
+            }
+        });
+    if let LightKind::Point { range } = &mut l.kind {
+        ui.add(egui::Slider::new(range, 0.01..=10.0).logarithmic(true).text("range"));
+    }
+    ui.horizontal(|ui| {
+        let mut rgb = l.color.to_array();
+        ui.color_edit_button_rgb(&mut rgb);
+        l.color = Vec3::from(rgb);
+        ui.add(egui::Slider::new(&mut l.intensity, 0.0..=10.0).text("intensity"));
+    });
+    if ui.button("remove light").clicked() {
+        *light = None;
+    }
+}
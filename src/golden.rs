@@ -0,0 +1,120 @@
+// Golden-image tests: renders reference scenes headless and compares them against
+// the pngs in assets/golden. The references are written by the same Engine::headless
+// path, run `PIXEL_BLESS=1 cargo test -- --ignored golden` after an intended change
+// of the look, then check the new pngs in.
+// The scene tests need a headless GL context, see headless::create_headless_context,
+// so they only run when asked for with `cargo test -- --ignored golden`.
+
+use std::path::PathBuf;
+
+use glam::*;
+
+use crate::engine::Engine;
+use crate::headless::RgbaImage;
+
+const SIZE: UVec2 = UVec2::new(200, 200);
+// per channel, absorbs rounding differences between GL implementations
+const TOLERANCE: u8 = 2;
+
+fn golden_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("assets/golden")
+}
+
+fn output_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target/golden")
+}
+
+// mismatching pixels in red over a faded copy of the expected image
+fn diff_image(expected: &RgbaImage, actual: &RgbaImage) -> (RgbaImage, usize) {
+    let mut diff = RgbaImage::new(expected.size);
+    let mut mismatches = 0;
+    for y in 0..expected.size.y {
+        for x in 0..expected.size.x {
+            let e = expected.pixel(x, y);
+            let a = actual.pixel(x, y);
+            let differs = e.iter().zip(a.iter()).any(|(&e, &a)| e.abs_diff(a) > TOLERANCE);
+            if differs {
+                mismatches += 1;
+                diff.set_pixel(x, y, [255, 0, 0, 255]);
+            } else {
+                diff.set_pixel(x, y, [e[0] / 4, e[1] / 4, e[2] / 4, 255]);
+            }
+        }
+    }
+    (diff, mismatches)
+}
+
+fn check_golden(name: &str, actual: &RgbaImage) {
+    let reference = golden_dir().join(format!("{}.png", name));
+
+    if std::env::var_os("PIXEL_BLESS").is_some() {
+        std::fs::create_dir_all(golden_dir()).unwrap();
+        actual.save_png(&reference).unwrap();
+        return;
+    }
+
+    let expected = RgbaImage::load_png(&reference).unwrap_or_else(|e| panic!(
+        "can't load {}: {}, run with PIXEL_BLESS=1 to create it", reference.display(), e));
+
+    let out = output_dir();
+    std::fs::create_dir_all(&out).unwrap();
+    let actual_path = out.join(format!("{}.actual.png", name));
+
+    if expected.size != actual.size {
+        actual.save_png(&actual_path).unwrap();
+        panic!("{}: size {} doesn't match the reference {}, actual output in {}", 
+            name, actual.size, expected.size, actual_path.display());
+    }
+
+    let (diff, mismatches) = diff_image(&expected, actual);
+    if mismatches > 0 {
+        let diff_path = out.join(format!("{}.diff.png", name));
+        actual.save_png(&actual_path).unwrap();
+        diff.save_png(&diff_path).unwrap();
+        panic!("{}: {} pixels differ from {} by more than {}, see {} and {}", 
+            name, mismatches, reference.display(), TOLERANCE, actual_path.display(), diff_path.display());
+    }
+}
+
+// a missing context fails the test, the scene tests are opted into
+fn headless_engine() -> Engine {
+    Engine::headless(SIZE).unwrap_or_else(|e| panic!("golden tests need a headless GL context: {}", e))
+}
+
+fn check_init_scene(name: &str, is_pixelated: bool) {
+    let mut engine = headless_engine();
+    engine.game_state.is_pixelated = is_pixelated;
+    check_golden(name, &engine.render_to_image().unwrap());
+}
+
+#[test]
+#[ignore = "needs a headless GL context, run with --ignored"]
+fn init_scene() {
+    check_init_scene("init_scene", false);
+}
+
+#[test]
+#[ignore = "needs a headless GL context, run with --ignored"]
+fn init_scene_pixelated() {
+    check_init_scene("init_scene_pixelated", true);
+}
+
+#[test]
+#[ignore = "needs a headless GL context, run with --ignored"]
+fn init_scene_palette() {
+    let mut engine = headless_engine();
+    engine.game_state.is_pixelated = true;
+    engine.game_state.palette = Some(engine.assets.palettes.handle("Game Boy").unwrap());
+    check_golden("init_scene_palette", &engine.render_to_image().unwrap());
+}
+
+#[test]
+fn diff_counts_only_pixels_above_tolerance() {
+    let expected = RgbaImage::new(UVec2::new(2, 1));
+    let mut actual = expected.clone();
+    actual.set_pixel(0, 0, [TOLERANCE, 0, 0, 0]);
+    actual.set_pixel(1, 0, [0, TOLERANCE + 1, 0, 0]);
+    let (diff, mismatches) = diff_image(&expected, &actual);
+    assert_eq!(mismatches, 1);
+    assert_eq!(diff.pixel(1, 0), [255, 0, 0, 255]);
+}
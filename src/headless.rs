@@ -0,0 +1,260 @@
+use std::path::Path;
+use std::rc::Rc;
+
+use glium::glutin;
+use glium::backend::Facade;
+use glium::texture::RawImage2d;
+use glam::*;
+
+#[derive(Debug)]
+pub enum HeadlessError {
+    // neither OSMesa nor EGL could make a context
+    Unavailable { osmesa: glutin::CreationError, egl: String },
+    Incompatible(glium::IncompatibleOpenGl),
+}
+
+impl std::fmt::Display for HeadlessError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HeadlessError::Unavailable { osmesa, egl } => write!(f, 
+                "failed to create headless context, needs libOSMesa or a libEGL.so.1 with surfaceless support \
+                (OSMesa: {}, EGL: {})", osmesa, egl),
+            HeadlessError::Incompatible(e) => write!(f, "headless context is not usable: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for HeadlessError {}
+
+// OSMesa renders on the cpu, so this works on build machines without a gpu or a display server.
+// Without it, Mesa's surfaceless EGL platform does the same, both render into framebuffer objects only
+#[cfg(unix)]
+pub fn create_headless_context(size: UVec2) -> Result<Rc<glium::backend::Context>, HeadlessError> {
+    use glutin::platform::unix::HeadlessContextExt;
+
+    let context = glutin::ContextBuilder::new()
+        .with_gl(glutin::GlRequest::Specific(glutin::Api::OpenGl, (3, 3)))
+        .with_gl_profile(glutin::GlProfile::Core)
+        .with_depth_buffer(24)
+        .build_osmesa(glutin::dpi::PhysicalSize::new(size.x, size.y));
+    let osmesa = match context {
+        Ok(context) => {
+            let renderer = glium::HeadlessRenderer::new(context).map_err(HeadlessError::Incompatible)?;
+            // the backend inside keeps the OSMesa context alive
+            return Ok(renderer.get_context().clone());
+        }
+        Err(e) => e,
+    };
+    let backend = egl::SurfacelessBackend::new(size).map_err(|egl| HeadlessError::Unavailable { osmesa, egl })?;
+    // safe as long as the backend's context is only made current through glium, which checks it before every call
+    unsafe { glium::backend::Context::new(backend, true, Default::default()) }.map_err(HeadlessError::Incompatible)
+}
+
+#[cfg(not(unix))]
+pub fn create_headless_context(_size: UVec2) -> Result<Rc<glium::backend::Context>, HeadlessError> {
+    Err(HeadlessError::Unavailable { 
+        osmesa: glutin::CreationError::NotSupported("OSMesa is unix only".to_owned()),
+        egl: "not supported on this platform".to_owned(),
+    })
+}
+
+// just enough of EGL for a GL 3.3 core context without any surface, loaded at runtime
+// so machines without libEGL still build and run everything else
+#[cfg(unix)]
+mod egl {
+    use std::ffi::{c_void, CStr, CString};
+    use std::os::raw::c_char;
+    use std::ptr::null_mut;
+
+    use glam::*;
+
+    type Display = *mut c_void;
+    type Context = *mut c_void;
+
+    const PLATFORM_SURFACELESS_MESA: u32 = 0x31DD;
+    const OPENGL_API: u32 = 0x30A2;
+    const CONTEXT_MAJOR_VERSION: i32 = 0x3098;
+    const CONTEXT_MINOR_VERSION: i32 = 0x30FB;
+    const CONTEXT_OPENGL_PROFILE_MASK: i32 = 0x30FD;
+    const CONTEXT_OPENGL_CORE_PROFILE_BIT: i32 = 1;
+    const NONE: i32 = 0x3038;
+    const TRUE: u32 = 1;
+
+    struct Egl {
+        get_error: unsafe extern "C" fn() -> i32,
+        get_proc_address: unsafe extern "C" fn(*const c_char) -> *const c_void,
+        make_current: unsafe extern "C" fn(Display, *mut c_void, *mut c_void, Context) -> u32,
+        get_current_context: unsafe extern "C" fn() -> Context,
+        destroy_context: unsafe extern "C" fn(Display, Context) -> u32,
+    }
+
+    impl Egl {
+        fn error(&self, call: &str) -> String {
+            format!("{} failed with 0x{:x}", call, unsafe { (self.get_error)() })
+        }
+    }
+
+    // the library stays loaded for the rest of the process, like the GL functions it hands out
+    unsafe fn symbol<T>(lib: *mut c_void, name: &str) -> Result<T, String> {
+        let c_name = CString::new(name).unwrap();
+        let ptr = libc::dlsym(lib, c_name.as_ptr());
+        if ptr.is_null() {
+            return Err(format!("libEGL.so.1 has no {}", name));
+        }
+        Ok(std::mem::transmute_copy(&ptr))
+    }
+
+    pub struct SurfacelessBackend {
+        egl: Egl,
+        display: Display,
+        context: Context,
+        size: UVec2,
+    }
+
+    impl SurfacelessBackend {
+        pub fn new(size: UVec2) -> Result<Self, String> {
+            unsafe {
+                let lib = libc::dlopen(c"libEGL.so.1".as_ptr(), libc::RTLD_NOW);
+                if lib.is_null() {
+                    return Err(CStr::from_ptr(libc::dlerror()).to_string_lossy().into_owned());
+                }
+                let get_platform_display: unsafe extern "C" fn(u32, *mut c_void, *const isize) -> Display = 
+                    symbol(lib, "eglGetPlatformDisplay")?;
+                let initialize: unsafe extern "C" fn(Display, *mut i32, *mut i32) -> u32 = symbol(lib, "eglInitialize")?;
+                let bind_api: unsafe extern "C" fn(u32) -> u32 = symbol(lib, "eglBindAPI")?;
+                let create_context: unsafe extern "C" fn(Display, *mut c_void, Context, *const i32) -> Context = 
+                    symbol(lib, "eglCreateContext")?;
+                let egl = Egl {
+                    get_error: symbol(lib, "eglGetError")?,
+                    get_proc_address: symbol(lib, "eglGetProcAddress")?,
+                    make_current: symbol(lib, "eglMakeCurrent")?,
+                    get_current_context: symbol(lib, "eglGetCurrentContext")?,
+                    destroy_context: symbol(lib, "eglDestroyContext")?,
+                };
+
+                let display = get_platform_display(PLATFORM_SURFACELESS_MESA, null_mut(), std::ptr::null());
+                if display.is_null() {
+                    return Err(egl.error("eglGetPlatformDisplay"));
+                }
+                let (mut major, mut minor) = (0, 0);
+                if initialize(display, &mut major, &mut minor) != TRUE {
+                    return Err(egl.error("eglInitialize"));
+                }
+                if bind_api(OPENGL_API) != TRUE {
+                    return Err(egl.error("eglBindAPI"));
+                }
+                // no config, EGL_KHR_no_config_context, nothing is ever drawn to a surface
+                let attributes = [
+                    CONTEXT_MAJOR_VERSION, 3,
+                    CONTEXT_MINOR_VERSION, 3,
+                    CONTEXT_OPENGL_PROFILE_MASK, CONTEXT_OPENGL_CORE_PROFILE_BIT,
+                    NONE,
+                ];
+                let context = create_context(display, null_mut(), null_mut(), attributes.as_ptr());
+                if context.is_null() {
+                    return Err(egl.error("eglCreateContext"));
+                }
+                let backend = SurfacelessBackend { egl, display, context, size };
+                if (backend.egl.make_current)(display, null_mut(), null_mut(), context) != TRUE {
+                    return Err(backend.egl.error("eglMakeCurrent"));
+                }
+                Ok(backend)
+            }
+        }
+    }
+
+    impl Drop for SurfacelessBackend {
+        fn drop(&mut self) {
+            unsafe {
+                if (self.egl.get_current_context)() == self.context {
+                    (self.egl.make_current)(self.display, null_mut(), null_mut(), null_mut());
+                }
+                (self.egl.destroy_context)(self.display, self.context);
+            }
+        }
+    }
+
+    unsafe impl glium::backend::Backend for SurfacelessBackend {
+        fn swap_buffers(&self) -> Result<(), glium::SwapBuffersError> {
+            Ok(())
+        }
+
+        unsafe fn get_proc_address(&self, symbol: &str) -> *const c_void {
+            let symbol = CString::new(symbol).unwrap();
+            (self.egl.get_proc_address)(symbol.as_ptr())
+        }
+
+        // there's no default framebuffer, this is what RenderState::render_offscreen renders at
+        fn get_framebuffer_dimensions(&self) -> (u32, u32) {
+            (self.size.x, self.size.y)
+        }
+
+        fn is_current(&self) -> bool {
+            unsafe { (self.egl.get_current_context)() == self.context }
+        }
+
+        unsafe fn make_current(&self) {
+            (self.egl.make_current)(self.display, null_mut(), null_mut(), self.context);
+        }
+    }
+}
+
+// 8 bit rgba, rows go top to bottom
+#[derive(Clone, Debug, PartialEq)]
+pub struct RgbaImage {
+    pub size: UVec2,
+    pub data: Vec<u8>,
+}
+
+impl RgbaImage {
+    pub fn new(size: UVec2) -> Self {
+        RgbaImage { size, data: vec![0; (size.x * size.y * 4) as usize] }
+    }
+
+    // gl hands rows out bottom to top
+    pub fn from_gl(raw: RawImage2d<u8>) -> Self {
+        let row = raw.width as usize * 4;
+        let data = raw.data.chunks_exact(row).rev().flatten().copied().collect();
+        RgbaImage { size: UVec2::new(raw.width, raw.height), data }
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 4] {
+        let i = ((y * self.size.x + x) * 4) as usize;
+        [self.data[i], self.data[i + 1], self.data[i + 2], self.data[i + 3]]
+    }
+
+    pub fn set_pixel(&mut self, x: u32, y: u32, p: [u8; 4]) {
+        let i = ((y * self.size.x + x) * 4) as usize;
+        self.data[i..i + 4].copy_from_slice(&p);
+    }
+
+    pub fn save_png(&self, path: impl AsRef<Path>) -> Result<(), png::EncodingError> {
+        let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        let mut encoder = png::Encoder::new(file, self.size.x, self.size.y);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.write_header()?.write_image_data(&self.data)
+    }
+}
+
+impl RgbaImage {
+    // any 8 or 16 bit png, converted to 8 bit rgba
+    pub fn load_png(path: impl AsRef<Path>) -> Result<Self, png::DecodingError> {
+        let mut decoder = png::Decoder::new(std::fs::File::open(path)?);
+        decoder.set_transformations(png::Transformations::normalize_to_color8());
+        let mut reader = decoder.read_info()?;
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buf)?;
+        buf.truncate(info.line_size * info.height as usize);
+
+        let data = match info.color_type {
+            png::ColorType::Rgba => buf,
+            png::ColorType::Rgb => buf.chunks_exact(3).flat_map(|p| [p[0], p[1], p[2], 255]).collect(),
+            png::ColorType::GrayscaleAlpha => buf.chunks_exact(2).flat_map(|p| [p[0], p[0], p[0], p[1]]).collect(),
+            png::ColorType::Grayscale => buf.iter().flat_map(|&g| [g, g, g, 255]).collect(),
+            // expanded by normalize_to_color8
+            png::ColorType::Indexed => unreachable!(),
+        };
+        Ok(RgbaImage { size: UVec2::new(info.width, info.height), data })
+    }
+}
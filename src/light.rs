@@ -0,0 +1,210 @@
+use glam::*;
+use glium::uniforms::{Uniforms, UniformValue};
+
+use crate::draw::Transform;
+use crate::game::GameObject;
+
+// keep in sync with assets/scene_fragment.glsl
+pub const MAX_LIGHTS: usize = 8;
+
+// the CPU versions of the scene shaders, see material::CpuMaterial
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Shading {
+    // smooth lambert, assets/lit.glsl
+    Lit,
+    // stepped diffuse and a rim, assets/toon.glsl
+    Toon,
+}
+
+impl Shading {
+    pub const ALL: [Shading; 2] = [Shading::Lit, Shading::Toon];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Shading::Lit => "Lit",
+            Shading::Toon => "Toon",
+        }
+    }
+    // the name the shader is registered under in Assets::shaders
+    pub fn shader_name(&self) -> &'static str {
+        match self {
+            Shading::Lit => "lit",
+            Shading::Toon => "toon",
+        }
+    }
+
+    // which CPU version the software renderer uses for a shader, Lit for anything it doesn't know
+    pub fn from_shader_name(name: &str) -> Shading {
+        Shading::ALL.into_iter().find(|s| s.shader_name() == name).unwrap_or(Shading::Lit)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LightKind {
+    // same everywhere, no direction
+    Ambient,
+    // shines along the object's -Z, position doesn't matter
+    Directional,
+    // from the object's position, fading out smoothly to nothing at `range`
+    Point { range: f32 },
+}
+
+impl LightKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LightKind::Ambient => "Ambient",
+            LightKind::Directional => "Directional",
+            LightKind::Point { .. } => "Point",
+        }
+    }
+
+    // the values assets/lit.glsl switches on
+    fn shader_id(&self) -> i32 {
+        match self {
+            LightKind::Ambient => 0,
+            LightKind::Directional => 1,
+            LightKind::Point { .. } => 2,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Light {
+    pub kind: LightKind,
+    // linear rgb
+    pub color: Vec3,
+    pub intensity: f32,
+}
+
+impl Light {
+    pub fn ambient(color: Vec3, intensity: f32) -> Light {
+        Light { kind: LightKind::Ambient, color, intensity }
+    }
+
+    pub fn directional(color: Vec3, intensity: f32) -> Light {
+        Light { kind: LightKind::Directional, color, intensity }
+    }
+
+    pub fn point(color: Vec3, intensity: f32, range: f32) -> Light {
+        Light { kind: LightKind::Point { range }, color, intensity }
+    }
+}
+
+// a light placed by its object's transform, in world space
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SceneLight {
+    pub kind: LightKind,
+    // direction towards the light for directional lights, position for point lights
+    pub vector: Vec3,
+    pub radiance: Vec3,
+    // set for the one light the shadow map is rendered from, see shadow::shadow_view_proj
+    pub shadowed: bool,
+}
+
+impl SceneLight {
+    pub fn new(light: &Light, transform: &Transform) -> SceneLight {
+        let vector = match light.kind {
+            LightKind::Ambient => Vec3::ZERO,
+            LightKind::Directional => (transform.rotation * Vec3::Z).normalize_or_zero(),
+            LightKind::Point { .. } => transform.position,
+        };
+        SceneLight { kind: light.kind, vector, radiance: light.color * light.intensity, shadowed: false }
+    }
+
+    // share of the radiance reaching a surface at `position` facing `normal`, in 0..1,
+    // keep in sync with assets/scene_fragment.glsl
+    pub fn diffuse(&self, position: Vec3, normal: Vec3) -> f32 {
+        match self.kind {
+            LightKind::Ambient => 1.,
+            LightKind::Directional => normal.dot(self.vector).max(0.),
+            LightKind::Point { range } => {
+                let to_light = self.vector - position;
+                let distance = to_light.length();
+                let falloff = (1. - (distance / range.max(f32::EPSILON)).powi(2)).clamp(0., 1.);
+                let n_dot_l = normal.dot(to_light.normalize_or_zero()).max(0.);
+                n_dot_l * falloff * falloff
+            }
+        }
+    }
+
+    pub fn irradiance(&self, position: Vec3, normal: Vec3) -> Vec3 {
+        self.radiance * self.diffuse(position, normal)
+    }
+
+    // `shadow` is the shadow map lookup at the surface, 1 when lit
+    pub fn visibility(&self, shadow: f32) -> f32 {
+        if self.shadowed { shadow } else { 1. }
+    }
+}
+
+// the first MAX_LIGHTS lights in the scene, the shader has no room for more
+pub fn collect_lights(game_objects: &[GameObject]) -> Vec<SceneLight> {
+    game_objects.iter()
+        .filter_map(|go| go.light.as_ref().map(|light| SceneLight::new(light, &go.transform)))
+        .take(MAX_LIGHTS)
+        .collect()
+}
+
+// CPU version of assets/lit.glsl, linear rgb
+pub fn shade(lights: &[SceneLight], base_color: Vec3, position: Vec3, normal: Vec3, shadow: f32) -> Vec3 {
+    let normal = normal.normalize_or_zero();
+    let irradiance = lights.iter()
+        .fold(Vec3::ZERO, |sum, l| sum + l.irradiance(position, normal) * l.visibility(shadow));
+    base_color * irradiance
+}
+
+pub struct LightUniforms<'a> {
+    pub lights: &'a [SceneLight],
+}
+
+impl<'a> Uniforms for LightUniforms<'a> {
+    fn visit_values<'b, F: FnMut(&str, UniformValue<'b>)>(&'b self, mut f: F) {
+        let lights = &self.lights[..self.lights.len().min(MAX_LIGHTS)];
+        f("u_light_count", UniformValue::SignedInt(lights.len() as i32));
+        for (i, light) in lights.iter().enumerate() {
+            let range = match light.kind {
+                LightKind::Point { range } => range,
+                _ => 0.,
+            };
+            f(&format!("u_light_kind[{}]", i), UniformValue::SignedInt(light.kind.shader_id()));
+            f(&format!("u_light_vector[{}]", i), UniformValue::Vec3(light.vector.to_array()));
+            f(&format!("u_light_radiance[{}]", i), UniformValue::Vec3(light.radiance.to_array()));
+            f(&format!("u_light_range[{}]", i), UniformValue::Float(range));
+            f(&format!("u_light_shadowed[{}]", i), UniformValue::Bool(light.shadowed));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(position: Vec3, rotation: Quat) -> Transform {
+        Transform { position, rotation, scale: Vec3::ONE }
+    }
+
+    #[test]
+    fn directional_light_follows_rotation() {
+        // pointing straight down, -Z turned onto -Y
+        let transform = at(Vec3::new(5., 5., 5.), Quat::from_rotation_arc(-Vec3::Z, -Vec3::Y));
+        let light = SceneLight::new(&Light::directional(Vec3::ONE, 2.), &transform);
+        assert!(light.vector.abs_diff_eq(Vec3::Y, 1e-6));
+        assert!(light.irradiance(Vec3::ZERO, Vec3::Y).abs_diff_eq(Vec3::splat(2.), 1e-6));
+        assert_eq!(light.irradiance(Vec3::ZERO, -Vec3::Y), Vec3::ZERO);
+    }
+
+    #[test]
+    fn point_light_fades_out_at_range() {
+        let light = SceneLight::new(&Light::point(Vec3::ONE, 1., 2.), &at(Vec3::Y, Quat::IDENTITY));
+        let near = light.irradiance(Vec3::ZERO, Vec3::Y).x;
+        let far = light.irradiance(Vec3::new(0., -0.5, 0.), Vec3::Y).x;
+        assert!(near > far && far > 0.);
+        assert_eq!(light.irradiance(Vec3::new(0., -1.5, 0.), Vec3::Y), Vec3::ZERO);
+    }
+
+    #[test]
+    fn ambient_ignores_normal() {
+        let lights = [SceneLight::new(&Light::ambient(Vec3::new(0.5, 0.25, 0.), 1.), &at(Vec3::ZERO, Quat::IDENTITY))];
+        assert_eq!(shade(&lights, Vec3::ONE, Vec3::ZERO, -Vec3::X, 1.), Vec3::new(0.5, 0.25, 0.));
+    }
+}
@@ -1,32 +1,1559 @@
 
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
 use glium::{VertexBuffer, IndexBuffer, index::PrimitiveType, Display, Surface, Program, uniforms::Uniforms, draw_parameters::DrawParameters};
+use glium::program::{ProgramCreationError, ShaderType};
 use glam::*;
 use crate::draw::*;
+use crate::game::GameObject;
+use crate::palette::Palette;
+use crate::headless::RgbaImage;
+use crate::material::{Material, Texture};
 
 #[derive(Default)]
 pub struct Assets 
 {
-    pub meshes: Vec<Mesh>,
-    pub shaders: Vec<Program>,
+    pub meshes: Storage<Mesh>,
+    // Mesh::bounds of the meshes added through add_mesh
+    pub mesh_bounds: HashMap<MeshHandle, Option<(Vec3, Vec3)>>,
+    pub shaders: Storage<Shader>,
+    pub textures: Storage<Texture>,
+    pub materials: Storage<Material>,
+    pub palettes: Storage<Palette>,
+    pub shader_watches: Vec<ShaderWatch>,
+}
+
+
+pub type MeshHandle = Handle<Mesh>;
+pub type ShaderHandle = Handle<Shader>;
+pub type TextureHandle = Handle<Texture>;
+pub type MaterialHandle = Handle<Material>;
+pub type PaletteHandle = Handle<Palette>;
+
+// index into a Storage, assets are never removed so a handle stays valid for the whole run
+pub struct Handle<T> {
+    index: usize,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Handle<T> {
+    // for tests that need a handle but never look it up
+    #[cfg(test)]
+    pub fn dangling(index: usize) -> Self {
+        Handle { index, _marker: PhantomData }
+    }
+}
+
+// derives would put bounds on T
+impl<T> Clone for Handle<T> {
+    fn clone(&self) -> Self { *self }
+}
+impl<T> Copy for Handle<T> {}
+impl<T> PartialEq for Handle<T> {
+    fn eq(&self, other: &Self) -> bool { self.index == other.index }
+}
+impl<T> Eq for Handle<T> {}
+impl<T> std::hash::Hash for Handle<T> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) { self.index.hash(state) }
+}
+impl<T> std::fmt::Debug for Handle<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Handle<{}>({})", short_type_name::<T>(), self.index)
+    }
+}
+
+fn short_type_name<T>() -> &'static str {
+    let name = std::any::type_name::<T>();
+    name.rsplit("::").next().unwrap_or(name)
+}
+
+#[derive(Debug)]
+pub enum AssetError {
+    MissingName { kind: &'static str, name: String },
+    MissingHandle { kind: &'static str, index: usize },
+}
+
+impl std::fmt::Display for AssetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AssetError::MissingName { kind, name } => write!(f, "no {} asset named '{}'", kind, name),
+            AssetError::MissingHandle { kind, index } => write!(f, "no {} asset with handle {}", kind, index),
+        }
+    }
+}
+
+impl std::error::Error for AssetError {}
+
+pub struct Storage<T> {
+    items: Vec<T>,
+    names: Vec<String>,
+    by_name: HashMap<String, usize>,
+    // bumped on every mutable access, so caches built from an item can tell it changed
+    generations: Vec<u64>,
+}
+
+impl<T> Default for Storage<T> {
+    fn default() -> Self {
+        Storage { items: Vec::new(), names: Vec::new(), by_name: HashMap::new(), generations: Vec::new() }
+    }
+}
+
+impl<T> Storage<T> {
+    // a name that is already taken now refers to the new asset, old handles keep the old one
+    pub fn add(&mut self, name: impl Into<String>, item: T) -> Handle<T> {
+        let name = name.into();
+        self.items.push(item);
+        self.by_name.insert(name.clone(), self.items.len() - 1);
+        self.names.push(name);
+        self.generations.push(0);
+        Handle { index: self.items.len() - 1, _marker: PhantomData }
+    }
+
+    pub fn handle(&self, name: &str) -> Result<Handle<T>, AssetError> {
+        self.by_name.get(name)
+            .map(|&index| Handle { index, _marker: PhantomData })
+            .ok_or_else(|| AssetError::MissingName { kind: short_type_name::<T>(), name: name.to_owned() })
+    }
+
+    pub fn try_get(&self, handle: Handle<T>) -> Result<&T, AssetError> {
+        self.items.get(handle.index)
+            .ok_or(AssetError::MissingHandle { kind: short_type_name::<T>(), index: handle.index })
+    }
+
+    // handles only come from `add`, so a miss means it came from another Assets instance
+    pub fn get(&self, handle: Handle<T>) -> &T {
+        self.try_get(handle).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn get_mut(&mut self, handle: Handle<T>) -> &mut T {
+        let kind = short_type_name::<T>();
+        let item = self.items.get_mut(handle.index)
+            .unwrap_or_else(|| panic!("{}", AssetError::MissingHandle { kind, index: handle.index }));
+        self.generations[handle.index] += 1;
+        item
+    }
+
+    pub fn get_by_name(&self, name: &str) -> Result<&T, AssetError> {
+        self.handle(name).map(|h| self.get(h))
+    }
+
+    // swaps the asset behind a handle, used for hot reloading
+    pub fn replace(&mut self, handle: Handle<T>, item: T) {
+        *self.get_mut(handle) = item;
+    }
+
+    pub fn name(&self, handle: Handle<T>) -> &str {
+        &self.names[handle.index]
+    }
+
+    pub fn generation(&self, handle: Handle<T>) -> u64 {
+        self.generations[handle.index]
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Handle<T>, &T)> {
+        self.items.iter().enumerate().map(|(index, item)| (Handle { index, _marker: PhantomData }, item))
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (Handle<T>, &mut T)> {
+        for generation in self.generations.iter_mut() {
+            *generation += 1;
+        }
+        self.items.iter_mut().enumerate().map(|(index, item)| (Handle { index, _marker: PhantomData }, item))
+    }
+}
+
+impl Assets {
+    // like meshes.add, but keeps the bounds so they aren't recomputed every frame,
+    // the positions of a mesh aren't expected to change once it's added
+    pub fn add_mesh(&mut self, name: impl Into<String>, mesh: Mesh) -> MeshHandle {
+        let bounds = mesh.bounds();
+        let handle = self.meshes.add(name, mesh);
+        self.mesh_bounds.insert(handle, bounds);
+        handle
+    }
+
+    // model space, falls back to going over the positions for meshes added without add_mesh
+    pub fn mesh_bounds(&self, handle: MeshHandle) -> Option<(Vec3, Vec3)> {
+        match self.mesh_bounds.get(&handle) {
+            Some(&bounds) => bounds,
+            None => self.meshes.get(handle).bounds(),
+        }
+    }
+}
+
+impl Assets {
+    // adds every group of the file as a separate mesh named `<file stem>/<group>`
+    pub fn load_obj(&mut self, path: impl AsRef<Path>) -> Result<Vec<MeshHandle>, ObjError> {
+        let path = path.as_ref();
+        let stem = file_stem(path);
+        let groups = load_obj(path)?;
+        Ok(groups.into_iter()
+            .map(|g| self.add_mesh(format!("{}/{}", stem, g.name), g.mesh))
+            .collect())
+    }
+}
+
+
+#[derive(Debug)]
+pub enum ObjError {
+    Io(std::io::Error),
+    Parse { file: String, line: usize, msg: String },
+}
+
+impl std::fmt::Display for ObjError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ObjError::Io(e) => write!(f, "{}", e),
+            ObjError::Parse { file, line, msg } => write!(f, "{}:{}: {}", file, line, msg),
+        }
+    }
+}
+
+impl std::error::Error for ObjError {}
+
+impl From<std::io::Error> for ObjError {
+    fn from(e: std::io::Error) -> Self {
+        ObjError::Io(e)
+    }
+}
+
+pub struct ObjGroup {
+    pub name: String,
+    pub mesh: Mesh,
+}
+
+pub fn load_obj(path: impl AsRef<Path>) -> Result<Vec<ObjGroup>, ObjError> {
+    let path = path.as_ref();
+    let src = std::fs::read_to_string(path)?;
+    parse_obj(&src, &path.display().to_string())
+}
+
+// `file` is only used for error messages
+pub fn parse_obj(src: &str, file: &str) -> Result<Vec<ObjGroup>, ObjError> {
+    let mut pos: Vec<Vec3> = Vec::new();
+    let mut nor: Vec<Vec3> = Vec::new();
+    let mut uv: Vec<Vec2> = Vec::new();
+
+    let mut groups = Vec::new();
+    let mut builder = ObjGroupBuilder::new("default");
+
+    for (line_i, line) in src.lines().enumerate() {
+        let err = |msg: String| ObjError::Parse { file: file.to_owned(), line: line_i + 1, msg };
+
+        let line = match line.find('#') {
+            Some(i) => &line[..i],
+            None => line,
+        };
+        let mut words = line.split_whitespace();
+        let keyword = match words.next() {
+            Some(w) => w,
+            None => continue,
+        };
+        let args: Vec<&str> = words.collect();
+
+        match keyword {
+            "v" => pos.push(parse_floats::<3>(&args, 3).map_err(err)?.into()),
+            "vn" => nor.push(parse_floats::<3>(&args, 3).map_err(err)?.into()),
+            // the third texture coordinate is optional and unused,
+            // v is flipped so the first image row is at v = 0 like in gltf
+            "vt" => {
+                let vt = Vec3::from(parse_floats::<3>(&args, 1).map_err(err)?);
+                uv.push(Vec2::new(vt.x, 1. - vt.y));
+            }
+            "f" => {
+                if args.len() < 3 {
+                    return Err(err(format!("face needs at least 3 vertices, got {}", args.len())));
+                }
+                let corners = args.iter()
+                    .map(|a| parse_face_corner(a, pos.len(), uv.len(), nor.len()))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(err)?;
+                builder.add_face(&corners, &pos, &uv, &nor);
+            }
+            "o" | "g" => {
+                let name = if args.is_empty() { "default".to_owned() } else { args.join(" ") };
+                if builder.is_empty() {
+                    builder.name = name;
+                } else {
+                    groups.push(std::mem::replace(&mut builder, ObjGroupBuilder::new(&name)).build());
+                }
+            }
+            // materials, smoothing groups and the rest don't affect geometry
+            _ => (),
+        }
+    }
+
+    if !builder.is_empty() {
+        groups.push(builder.build());
+    }
+
+    Ok(groups)
+}
+
+// values past N are ignored, `v x y z w` and the `v x y z r g b` vertex color extension are common
+fn parse_floats<const N: usize>(args: &[&str], required: usize) -> Result<[f32; N], String> {
+    if args.len() < required {
+        return Err(format!("expected at least {} numbers, got {}", required, args.len()));
+    }
+    let mut out = [0.; N];
+    for (o, a) in out.iter_mut().zip(args) {
+        *o = a.parse().map_err(|_| format!("invalid number '{}'", a))?;
+    }
+    Ok(out)
+}
+
+// resolves 1-based and negative (relative to the end) indices into 0-based ones
+fn resolve_index(s: &str, count: usize) -> Result<usize, String> {
+    let i: i64 = s.parse().map_err(|_| format!("invalid index '{}'", s))?;
+    let resolved = if i > 0 { i - 1 } else { count as i64 + i };
+    if i == 0 || resolved < 0 || resolved >= count as i64 {
+        return Err(format!("index {} out of range, {} elements defined", i, count));
+    }
+    Ok(resolved as usize)
+}
+
+// v, v/vt, v//vn or v/vt/vn
+fn parse_face_corner(s: &str, pos_count: usize, uv_count: usize, nor_count: usize) 
+    -> Result<(usize, Option<usize>, Option<usize>), String> 
+{
+    let mut parts = s.split('/');
+    let v = resolve_index(parts.next().unwrap(), pos_count)?;
+    let vt = match parts.next() {
+        Some("") | None => None,
+        Some(p) => Some(resolve_index(p, uv_count)?),
+    };
+    let vn = match parts.next() {
+        Some("") | None => None,
+        Some(p) => Some(resolve_index(p, nor_count)?),
+    };
+    if parts.next().is_some() {
+        return Err(format!("invalid face vertex '{}'", s));
+    }
+    Ok((v, vt, vn))
+}
+
+struct ObjGroupBuilder {
+    name: String,
+    mesh: Mesh,
+    has_uv: bool,
+    has_nor: bool,
+    // obj indexes every attribute separately, we need one index per unique combination
+    vertices: std::collections::HashMap<(usize, Option<usize>, Option<usize>), u32>,
+}
+
+impl ObjGroupBuilder {
+    fn new(name: &str) -> Self {
+        ObjGroupBuilder {
+            name: name.to_owned(),
+            mesh: Mesh { pos: Vec::new(), nor: Vec::new(), uv: Vec::new(), ind: Vec::new() },
+            has_uv: false,
+            has_nor: false,
+            vertices: Default::default(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.mesh.ind.is_empty()
+    }
+
+    fn add_face(&mut self, corners: &[(usize, Option<usize>, Option<usize>)], pos: &[Vec3], uv: &[Vec2], nor: &[Vec3]) {
+        let mut ind = Vec::with_capacity(corners.len());
+        for &key in corners {
+            let (v, vt, vn) = key;
+            let mesh = &mut self.mesh;
+            let i = *self.vertices.entry(key).or_insert_with(|| {
+                mesh.pos.push(pos[v]);
+                mesh.nor.push(vn.map_or(Vec3::ZERO, |i| nor[i]));
+                mesh.uv.push(vt.map_or(Vec2::ZERO, |i| uv[i]));
+                (mesh.pos.len() - 1) as u32
+            });
+            self.has_uv |= vt.is_some();
+            self.has_nor |= vn.is_some();
+            ind.push(i);
+        }
+        // fan triangulation, fine for the convex polygons blender exports
+        for k in 1..ind.len() - 1 {
+            self.mesh.ind.extend([ind[0], ind[k], ind[k + 1]]);
+        }
+    }
+
+    fn build(mut self) -> ObjGroup {
+        if !self.has_nor { self.mesh.nor.clear(); }
+        if !self.has_uv { self.mesh.uv.clear(); }
+        ObjGroup { name: self.name, mesh: self.mesh }
+    }
+}
+
+
+impl Assets {
+    // every gltf mesh becomes one Mesh in Assets::meshes (all of its primitives merged),
+    // every node of the default scene that has a mesh becomes a game object drawn with `material`
+    pub fn load_gltf(&mut self, path: impl AsRef<Path>, material: MaterialHandle) -> Result<Vec<GameObject>, GltfError> {
+        let path = path.as_ref();
+        let stem = file_stem(path);
+        // only the buffers are read, so a missing or broken texture doesn't keep the meshes from loading
+        let gltf::Gltf { document, blob } = gltf::Gltf::open(path)?;
+        let buffers = gltf::import_buffers(&document, path.parent(), blob)?;
+
+        let scene = document.default_scene()
+            .or_else(|| document.scenes().next())
+            .ok_or_else(|| GltfError::Invalid("file has no scenes".to_owned()))?;
+
+        // nothing is added to the assets until the whole file loaded
+        let mut loaded = Vec::new();
+        for mesh in document.meshes() {
+            let name = format!("{}/{}", stem, mesh.name().map_or_else(|| mesh.index().to_string(), str::to_owned));
+            loaded.push((name, load_gltf_mesh(&mesh, &buffers)?));
+        }
+        let meshes: Vec<MeshHandle> = loaded.into_iter()
+            .map(|(name, mesh)| self.add_mesh(name, mesh))
+            .collect();
+
+        let mut game_objects = Vec::new();
+        for node in scene.nodes() {
+            collect_gltf_nodes(&node, Mat4::IDENTITY, &meshes, material, &mut game_objects);
+        }
+        Ok(game_objects)
+    }
+}
+
+#[derive(Debug)]
+pub enum GltfError {
+    Gltf(gltf::Error),
+    Invalid(String),
+}
+
+impl std::fmt::Display for GltfError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GltfError::Gltf(e) => write!(f, "{}", e),
+            GltfError::Invalid(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for GltfError {}
+
+impl From<gltf::Error> for GltfError {
+    fn from(e: gltf::Error) -> Self {
+        GltfError::Gltf(e)
+    }
+}
+
+fn load_gltf_mesh(mesh: &gltf::Mesh, buffers: &[gltf::buffer::Data]) -> Result<Mesh, GltfError> {
+    let mesh_name = mesh.name().unwrap_or("unnamed");
+    let mut out = Mesh { pos: Vec::new(), nor: Vec::new(), uv: Vec::new(), ind: Vec::new() };
+    let mut has_nor = false;
+    let mut has_uv = false;
+
+    for primitive in mesh.primitives() {
+        if primitive.mode() != gltf::mesh::Mode::Triangles {
+            return Err(GltfError::Invalid(format!(
+                "mesh '{}': only triangle lists are supported, got {:?}", mesh_name, primitive.mode())));
+        }
+        let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+
+        let base = out.pos.len() as u32;
+        let pos: Vec<Vec3> = reader.read_positions()
+            .ok_or_else(|| GltfError::Invalid(format!("mesh '{}': primitive without positions", mesh_name)))?
+            .map(Vec3::from)
+            .collect();
+
+        match reader.read_normals() {
+            Some(normals) => {
+                // earlier primitives without normals get zeros so the streams stay aligned
+                out.nor.resize(out.pos.len(), Vec3::ZERO);
+                out.nor.extend(normals.map(Vec3::from));
+                has_nor = true;
+            }
+            None if has_nor => out.nor.extend(std::iter::repeat(Vec3::ZERO).take(pos.len())),
+            None => (),
+        }
+
+        match reader.read_tex_coords(0) {
+            Some(uv) => {
+                out.uv.resize(out.pos.len(), Vec2::ZERO);
+                out.uv.extend(uv.into_f32().map(Vec2::from));
+                has_uv = true;
+            }
+            None if has_uv => out.uv.extend(std::iter::repeat(Vec2::ZERO).take(pos.len())),
+            None => (),
+        }
+
+        match reader.read_indices() {
+            Some(indices) => out.ind.extend(indices.into_u32().map(|i| base + i)),
+            None => out.ind.extend((0..pos.len() as u32).map(|i| base + i)),
+        }
+        out.pos.extend(pos);
+    }
+
+    if has_nor {
+        out.nor.resize(out.pos.len(), Vec3::ZERO);
+    }
+    if has_uv {
+        out.uv.resize(out.pos.len(), Vec2::ZERO);
+    }
+    Ok(out)
 }
 
+fn collect_gltf_nodes(node: &gltf::Node, parent: Mat4, meshes: &[MeshHandle], material: MaterialHandle, out: &mut Vec<GameObject>) {
+    let global = parent * Mat4::from_cols_array_2d(&node.transform().matrix());
+
+    if let Some(mesh) = node.mesh() {
+        let (scale, rotation, position) = global.to_scale_rotation_translation();
+        let name = node.name()
+            .or(mesh.name())
+            .map(str::to_owned)
+            .unwrap_or_else(|| format!("node {}", node.index()));
+        out.push(GameObject {
+            name,
+            transform: Transform { position, rotation, scale },
+            mesh: Some(meshes[mesh.index()]),
+            material,
+            light: None,
+        });
+    }
+
+    for child in node.children() {
+        collect_gltf_nodes(&child, global, meshes, material, out);
+    }
+}
+
+
+// a shader in Assets::shaders, the programs are compiled from the source by Assets::create_gl_resources
+pub struct Shader {
+    pub source: ShaderSource,
+    pub program: Option<Program>,
+    // the same shader compiled with INSTANCED defined, for the ones whose source checks it
+    pub instanced: Option<Program>,
+}
+
+impl Shader {
+    pub fn new(source: ShaderSource) -> Self {
+        Shader { source, program: None, instanced: None }
+    }
+
+    // both variants or neither, so the pair never goes out of sync
+    pub fn compile(&mut self, display: &GlContext) -> Result<(), ShaderError> {
+        let program = self.source.compile(display)?;
+        let instanced = self.source.compile_instanced(display)?;
+        self.program = Some(program);
+        self.instanced = instanced;
+        Ok(())
+    }
+
+    // only the GL renderer draws with shaders, and it has created them by then
+    pub fn program(&self) -> &Program {
+        match &self.program {
+            Some(program) => program,
+            None => panic!("{} has no program, see Assets::create_gl_resources", self.source.files[0]),
+        }
+    }
+}
 
 impl Assets {
-    // const unsafe fn uninit() -> Self {
-    //     const_zero::const_zero!(RenderState)
-    // }
-    
-    pub fn init() {
-        unsafe {
-            assets = Some(Default::default());
+    pub fn add_shader(&mut self, name: impl Into<String>, source: ShaderSource) -> ShaderHandle {
+        self.shaders.add(name, Shader::new(source))
+    }
+
+    // compiles the shaders and uploads the textures that aren't on the GPU yet,
+    // everything before this works without a GL context
+    pub fn create_gl_resources(&mut self, display: &GlContext) -> Vec<GlResourceError> {
+        let mut errors = Vec::new();
+        for (_, shader) in self.shaders.iter_mut() {
+            if shader.program.is_some() {
+                continue;
+            }
+            if let Err(e) = shader.compile(display) {
+                errors.push(GlResourceError::Shader(e));
+            }
         }
+        let mut failed = Vec::new();
+        for (handle, texture) in self.textures.iter_mut() {
+            if texture.gpu.is_some() {
+                continue;
+            }
+            if let Err(e) = texture.upload(display) {
+                failed.push((handle, e));
+            }
+        }
+        errors.extend(failed.into_iter().map(|(handle, error)| 
+            GlResourceError::Texture { name: self.textures.name(handle).to_owned(), error }));
+        errors
     }
+}
 
-    pub fn get() -> &'static mut Self {
-        unsafe { assets.as_mut().unwrap() }
+#[derive(Debug)]
+pub enum GlResourceError {
+    Shader(ShaderError),
+    Texture { name: String, error: glium::texture::TextureCreationError },
+}
+
+impl std::fmt::Display for GlResourceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GlResourceError::Shader(e) => write!(f, "{}", e),
+            GlResourceError::Texture { name, error } => write!(f, "texture {}: {}", name, error),
+        }
+    }
+}
+
+impl std::error::Error for GlResourceError {}
+
+#[derive(Debug)]
+pub enum ShaderError {
+    Io(std::io::Error),
+    Parse { file: String, line: usize, msg: String },
+    // the log has its line numbers already mapped back to the original file
+    Compile { file: String, stage: ShaderType, log: String },
+    Link { file: String, log: String },
+    Program { file: String, error: ProgramCreationError },
+}
+
+impl std::fmt::Display for ShaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ShaderError::Io(e) => write!(f, "{}", e),
+            ShaderError::Parse { file, line, msg } => write!(f, "{}:{}: {}", file, line, msg),
+            ShaderError::Compile { file, stage, log } => 
+                write!(f, "{}: {:?} shader failed to compile:\n{}", file, stage, log),
+            ShaderError::Link { file, log } => write!(f, "{}: program failed to link:\n{}", file, log),
+            ShaderError::Program { file, error } => write!(f, "{}: {}", file, error),
+        }
+    }
+}
+
+impl std::error::Error for ShaderError {}
+
+impl From<std::io::Error> for ShaderError {
+    fn from(e: std::io::Error) -> Self {
+        ShaderError::Io(e)
     }
 }
 
-static mut assets: Option<Assets> = None;
+pub struct ShaderStageSource {
+    pub src: String,
+    // index into ShaderSource::files and 1-based line there, for every line of `src`
+    pub lines: Vec<(usize, usize)>,
+}
+
+// single file with `#shader vertex`, `#shader fragment` and optionally `#shader geometry` sections,
+// `#include "file"` inside a section pastes in a file next to it
+pub struct ShaderSource {
+    // the shader itself first, then everything it includes
+    pub files: Vec<String>,
+    pub vertex: ShaderStageSource,
+    pub fragment: ShaderStageSource,
+    pub geometry: Option<ShaderStageSource>,
+}
+
+// guards against files including each other
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+// the snippets shared by the shaders in assets, embedded for the same reason as the shaders
+const EMBEDDED_SHADER_INCLUDES: [(&str, &str); 3] = [
+    ("instancing.glsl", include_str!("../assets/instancing.glsl")),
+    ("scene_vertex.glsl", include_str!("../assets/scene_vertex.glsl")),
+    ("scene_fragment.glsl", include_str!("../assets/scene_fragment.glsl")),
+];
+
+impl ShaderSource {
+    // a shader kept in a string per stage, like the ones in assets/shaders.rs, `file` only names it in errors
+    pub fn from_stages(file: &str, vertex: &str, fragment: &str) -> ShaderSource {
+        let stage = |src: &str| {
+            let mut stage = ShaderStageSource { src: String::new(), lines: Vec::new() };
+            for (i, line) in src.lines().enumerate() {
+                stage.push(line, (0, i + 1));
+            }
+            stage
+        };
+        ShaderSource { files: vec![file.to_owned()], vertex: stage(vertex), fragment: stage(fragment), geometry: None }
+    }
+
+    // includes are read next to `file` on disk so they reload with it, or else taken from the embedded copies
+    pub fn parse(src: &str, file: &str) -> Result<ShaderSource, ShaderError> {
+        Self::parse_with(src, file, &mut |name| {
+            match std::fs::read_to_string(Path::new(file).with_file_name(name)) {
+                Ok(src) => Some(src),
+                Err(_) => EMBEDDED_SHADER_INCLUDES.iter()
+                    .find(|(n, _)| *n == name)
+                    .map(|(_, src)| src.to_string()),
+            }
+        })
+    }
+
+    // `include` returns the source of an included file by name
+    pub fn parse_with(src: &str, file: &str, include: &mut dyn FnMut(&str) -> Option<String>) 
+        -> Result<ShaderSource, ShaderError> 
+    {
+        let err = |line: usize, msg: String| ShaderError::Parse { file: file.to_owned(), line, msg };
+
+        let mut files = vec![file.to_owned()];
+        let mut stages: [Option<ShaderStageSource>; 3] = [None, None, None];
+        let mut current: Option<usize> = None;
 
-// TODO(mb): mesh loading
\ No newline at end of file
+        for (line_i, line) in src.lines().enumerate() {
+            let line_n = line_i + 1;
+            if let Some(rest) = line.trim_start().strip_prefix("#shader") {
+                let stage = match rest.trim() {
+                    "vertex" => 0,
+                    "fragment" | "pixel" => 1,
+                    "geometry" => 2,
+                    other => return Err(err(line_n, format!("unknown shader stage '{}'", other))),
+                };
+                if stages[stage].is_some() {
+                    return Err(err(line_n, format!("stage '{}' defined twice", rest.trim())));
+                }
+                stages[stage] = Some(ShaderStageSource { src: String::new(), lines: Vec::new() });
+                current = Some(stage);
+                continue;
+            }
+
+            match current {
+                Some(stage) => {
+                    let stage = stages[stage].as_mut().unwrap();
+                    push_shader_line(stage, &mut files, include, line, (0, line_n), 0)?;
+                }
+                None if line.trim().is_empty() || line.trim_start().starts_with("//") => (),
+                None => return Err(err(line_n, "code outside of a #shader section".to_owned())),
+            }
+        }
+
+        let [vertex, fragment, geometry] = stages;
+        Ok(ShaderSource {
+            vertex: vertex.ok_or_else(|| err(1, "missing #shader vertex section".to_owned()))?,
+            fragment: fragment.ok_or_else(|| err(1, "missing #shader fragment section".to_owned()))?,
+            geometry,
+            files,
+        })
+    }
+
+    pub fn compile(&self, display: &GlContext) -> Result<Program, ShaderError> {
+        let file = &self.files[0];
+        let program = Program::from_source(display, 
+            &self.vertex.src, 
+            &self.fragment.src, 
+            self.geometry.as_ref().map(|g| g.src.as_str()));
+
+        program.map_err(|e| match e {
+            ProgramCreationError::CompilationError(log, stage) => {
+                let lines = match stage {
+                    ShaderType::Vertex => &self.vertex.lines[..],
+                    ShaderType::Fragment => &self.fragment.lines[..],
+                    ShaderType::Geometry => self.geometry.as_ref().map_or(&[][..], |g| &g.lines[..]),
+                    _ => &[],
+                };
+                ShaderError::Compile { 
+                    file: file.clone(), 
+                    stage, 
+                    log: remap_shader_log(&log, &self.files, lines),
+                }
+            }
+            ProgramCreationError::LinkingError(log) => ShaderError::Link { file: file.clone(), log },
+            error => ShaderError::Program { file: file.clone(), error },
+        })
+    }
+
+    // the same source with `#define name` after the #version of every stage
+    pub fn with_define(&self, name: &str) -> ShaderSource {
+        ShaderSource {
+            files: self.files.clone(),
+            vertex: self.vertex.with_define(name),
+            fragment: self.fragment.with_define(name),
+            geometry: self.geometry.as_ref().map(|g| g.with_define(name)),
+        }
+    }
+
+    // None for sources that never check INSTANCED, those are drawn the same either way
+    pub fn compile_instanced(&self, display: &GlContext) -> Result<Option<Program>, ShaderError> {
+        let stages = [Some(&self.vertex), Some(&self.fragment), self.geometry.as_ref()];
+        if !stages.iter().flatten().any(|stage| stage.src.contains("INSTANCED")) {
+            return Ok(None);
+        }
+        self.with_define("INSTANCED").compile(display).map(Some)
+    }
+}
+
+impl ShaderStageSource {
+    fn with_define(&self, name: &str) -> ShaderStageSource {
+        let at = match self.src.lines().position(|line| line.trim_start().starts_with("#version")) {
+            Some(version) => version + 1,
+            None => 0,
+        };
+        // errors in the define point at the #version line
+        let origin = self.lines.get(at.max(1) - 1).copied().unwrap_or((0, 1));
+        let define = format!("#define {}", name);
+
+        let mut out = ShaderStageSource { src: String::new(), lines: Vec::new() };
+        for (i, (line, &line_origin)) in self.src.lines().zip(self.lines.iter()).enumerate() {
+            if i == at {
+                out.push(&define, origin);
+            }
+            out.push(line, line_origin);
+        }
+        if at >= self.lines.len() {
+            out.push(&define, origin);
+        }
+        out
+    }
+
+    fn push(&mut self, line: &str, origin: (usize, usize)) {
+        self.src.push_str(line);
+        self.src.push('\n');
+        self.lines.push(origin);
+    }
+}
+
+// appends `line` that came from `origin`, expanding it first if it's an `#include`
+fn push_shader_line(stage: &mut ShaderStageSource, files: &mut Vec<String>, 
+    include: &mut dyn FnMut(&str) -> Option<String>, line: &str, origin: (usize, usize), depth: usize) 
+    -> Result<(), ShaderError> 
+{
+    let rest = match line.trim_start().strip_prefix("#include") {
+        Some(rest) => rest,
+        None => {
+            stage.push(line, origin);
+            return Ok(());
+        }
+    };
+
+    let err = |msg: String| ShaderError::Parse { file: files[origin.0].clone(), line: origin.1, msg };
+    let name = match rest.trim().strip_prefix('"').and_then(|r| r.strip_suffix('"')) {
+        Some(name) => name,
+        None => return Err(err("expected #include \"file\"".to_owned())),
+    };
+    if depth >= MAX_INCLUDE_DEPTH {
+        return Err(err(format!("#include '{}' nested too deep", name)));
+    }
+    let src = match include(name) {
+        Some(src) => src,
+        None => return Err(err(format!("can't include '{}'", name))),
+    };
+
+    let path = Path::new(&files[0]).with_file_name(name).display().to_string();
+    let file_i = match files.iter().position(|f| *f == path) {
+        Some(i) => i,
+        None => {
+            files.push(path);
+            files.len() - 1
+        }
+    };
+    for (line_i, line) in src.lines().enumerate() {
+        if line.trim_start().starts_with("#shader") {
+            return Err(ShaderError::Parse { 
+                file: files[file_i].clone(), 
+                line: line_i + 1, 
+                msg: "#shader in an included file".to_owned(),
+            });
+        }
+        push_shader_line(stage, files, include, line, (file_i, line_i + 1), depth + 1)?;
+    }
+    Ok(())
+}
+
+pub fn load_shader_file(path: impl AsRef<Path>) -> Result<ShaderSource, ShaderError> {
+    let path = path.as_ref();
+    let src = std::fs::read_to_string(path)?;
+    ShaderSource::parse(&src, &path.display().to_string())
+}
+
+// drivers report stage-local locations as `0:12(5):` (mesa), `0(12) :` (nvidia) or `ERROR: 0:12:`,
+// the first one on every line is rewritten into `file:line` of the file the line came from
+fn remap_shader_log(log: &str, files: &[String], lines: &[(usize, usize)]) -> String {
+    let mut out = String::with_capacity(log.len());
+    for line in log.lines() {
+        let location = match find_shader_log_location(line) {
+            Some((start, end, n)) if n >= 1 && n <= lines.len() => Some((start, end, lines[n - 1])),
+            _ => None,
+        };
+        match location {
+            Some((start, end, (file, n))) => {
+                out.push_str(&line[..start]);
+                out.push_str(&format!("{}:{}", files[file], n));
+                out.push_str(&line[end..]);
+            }
+            None => out.push_str(line),
+        }
+        out.push('\n');
+    }
+    out
+}
+
+// byte range of the location and the stage-local line number
+fn find_shader_log_location(line: &str) -> Option<(usize, usize, usize)> {
+    let bytes = line.as_bytes();
+    for start in 0..bytes.len() {
+        if bytes[start] != b'0' || (start > 0 && bytes[start - 1].is_ascii_alphanumeric()) {
+            continue;
+        }
+        let open = match bytes.get(start + 1) {
+            Some(&c) if c == b':' || c == b'(' => c,
+            _ => continue,
+        };
+        let digits_start = start + 2;
+        let digits_end = digits_start + bytes[digits_start..].iter().take_while(|c| c.is_ascii_digit()).count();
+        if digits_end == digits_start {
+            continue;
+        }
+        let end = match open {
+            b'(' if bytes.get(digits_end) == Some(&b')') => digits_end + 1,
+            b'(' => continue,
+            _ => digits_end,
+        };
+        let n = line[digits_start..digits_end].parse().ok()?;
+        return Some((start, end, n));
+    }
+    None
+}
+
+
+// where the files init loads live: $PIXEL_ASSETS, or else `assets` in the working directory,
+// which is the crate root under `cargo run`
+pub fn asset_root() -> PathBuf {
+    match std::env::var_os("PIXEL_ASSETS") {
+        Some(root) => PathBuf::from(root),
+        None => PathBuf::from("assets"),
+    }
+}
+
+// shader in Assets::shaders that gets recompiled when its file or anything it includes changes on disk
+pub struct ShaderWatch {
+    pub shader: ShaderHandle,
+    pub path: PathBuf,
+    pub includes: Vec<PathBuf>,
+    // latest of the file and its includes
+    pub modified: Option<SystemTime>,
+    // last failed compile or missing file, the previous program stays in use meanwhile
+    pub error: Option<String>,
+}
+
+impl ShaderWatch {
+    fn files(&self) -> impl Iterator<Item = &PathBuf> {
+        std::iter::once(&self.path).chain(self.includes.iter())
+    }
+
+    fn latest_modified(&self) -> Option<SystemTime> {
+        self.files().filter_map(|p| file_modified(p)).max()
+    }
+}
+
+impl Assets {
+    // `src` is the embedded copy of the file, so startup doesn't depend on the working directory
+    pub fn add_watched_shader(&mut self, name: &str, path: impl Into<PathBuf>, src: &str) 
+        -> Result<ShaderHandle, ShaderError> 
+    {
+        let path = path.into();
+        let source = ShaderSource::parse(src, &path.display().to_string())?;
+        let includes = source.files[1..].iter().map(PathBuf::from).collect();
+        let shader = self.add_shader(name, source);
+        let mut watch = ShaderWatch {
+            shader,
+            path,
+            includes,
+            modified: None,
+            error: None,
+        };
+        watch.modified = watch.latest_modified();
+        self.shader_watches.push(watch);
+        Ok(shader)
+    }
+
+    // polls the mtimes of every watched file, cheap enough to call each frame,
+    // without a context only the source is replaced
+    pub fn reload_shaders(&mut self, display: Option<&GlContext>) {
+        for watch in self.shader_watches.iter_mut() {
+            // includes that were only found embedded count as missing too
+            let missing = watch.files().find(|p| !p.exists()).cloned();
+            if let Some(missing) = missing {
+                watch.error = Some(format!("{}: watched file is missing, {} isn't reloaded", 
+                    missing.display(), watch.path.display()));
+                // reloads as soon as it's back
+                watch.modified = None;
+                continue;
+            }
+            let modified = watch.latest_modified();
+            if modified == watch.modified {
+                continue;
+            }
+            watch.modified = modified;
+
+            let source = match load_shader_file(&watch.path) {
+                Ok(source) => source,
+                Err(e) => {
+                    watch.error = Some(e.to_string());
+                    continue;
+                }
+            };
+            // an edit can add includes, those are watched from now on
+            watch.includes = source.files[1..].iter().map(PathBuf::from).collect();
+            let mut shader = Shader::new(source);
+            let compiled = match display {
+                Some(display) => shader.compile(display),
+                None => Ok(()),
+            };
+            match compiled {
+                Ok(()) => {
+                    self.shaders.replace(watch.shader, shader);
+                    watch.error = None;
+                }
+                Err(e) => watch.error = Some(e.to_string()),
+            }
+        }
+    }
+}
+
+impl Assets {
+    // the texture is named after the file stem
+    pub fn load_texture(&mut self, path: impl AsRef<Path>) -> Result<TextureHandle, TextureError> {
+        let path = path.as_ref();
+        let texture = Texture::new(RgbaImage::load_png(path)?);
+        Ok(self.textures.add(file_stem(path), texture))
+    }
+}
+
+#[derive(Debug)]
+pub enum TextureError {
+    Png(png::DecodingError),
+}
+
+impl std::fmt::Display for TextureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TextureError::Png(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for TextureError {}
+
+impl From<png::DecodingError> for TextureError {
+    fn from(e: png::DecodingError) -> Self {
+        TextureError::Png(e)
+    }
+}
+
+impl Assets {
+    // the palette is named after the file stem
+    pub fn load_palette(&mut self, path: impl AsRef<Path>) -> Result<PaletteHandle, PaletteError> {
+        let path = path.as_ref();
+        let palette = load_palette(path)?;
+        Ok(self.palettes.add(file_stem(path), palette))
+    }
+
+    // every palette file in `dir` in name order, files that fail are skipped with their error
+    pub fn load_palette_dir(&mut self, dir: impl AsRef<Path>) -> Vec<PaletteError> {
+        let mut paths: Vec<PathBuf> = match std::fs::read_dir(dir) {
+            Ok(entries) => entries.filter_map(|e| e.ok()).map(|e| e.path()).collect(),
+            Err(e) => return vec![PaletteError::Io(e)],
+        };
+        paths.sort();
+        paths.iter()
+            .filter(|path| path.is_file())
+            .filter_map(|path| self.load_palette(path).err())
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub enum PaletteError {
+    Io(std::io::Error),
+    Png(png::DecodingError),
+    Parse { file: String, line: usize, msg: String },
+    UnknownFormat { file: String },
+    NotAStrip { file: String, size: UVec2 },
+    Empty { file: String },
+}
+
+impl std::fmt::Display for PaletteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PaletteError::Io(e) => write!(f, "{}", e),
+            PaletteError::Png(e) => write!(f, "{}", e),
+            PaletteError::Parse { file, line, msg } => write!(f, "{}:{}: {}", file, line, msg),
+            PaletteError::UnknownFormat { file } => 
+                write!(f, "{}: unknown palette format, expected .gpl, .hex, .pal, .txt or .png", file),
+            PaletteError::NotAStrip { file, size } => 
+                write!(f, "{}: palette images must be 1 pixel high or wide, got {}x{}", file, size.x, size.y),
+            PaletteError::Empty { file } => write!(f, "{}: palette has no colors", file),
+        }
+    }
+}
+
+impl std::error::Error for PaletteError {}
+
+impl From<std::io::Error> for PaletteError {
+    fn from(e: std::io::Error) -> Self {
+        PaletteError::Io(e)
+    }
+}
+
+impl From<png::DecodingError> for PaletteError {
+    fn from(e: png::DecodingError) -> Self {
+        PaletteError::Png(e)
+    }
+}
+
+// picks the format by extension
+pub fn load_palette(path: impl AsRef<Path>) -> Result<Palette, PaletteError> {
+    let path = path.as_ref();
+    let file = path.display().to_string();
+    let ext = path.extension().map(|e| e.to_string_lossy().to_ascii_lowercase());
+
+    let colors = match ext.as_deref() {
+        Some("png") => palette_from_strip(&RgbaImage::load_png(path)?, &file)?,
+        Some("gpl") => parse_gpl(&std::fs::read_to_string(path)?, &file)?,
+        Some("hex") => parse_hex_palette(&std::fs::read_to_string(path)?, &file)?,
+        Some("pal") => parse_jasc_pal(&std::fs::read_to_string(path)?, &file)?,
+        Some("txt") => parse_paint_net_palette(&std::fs::read_to_string(path)?, &file)?,
+        _ => return Err(PaletteError::UnknownFormat { file }),
+    };
+    if colors.is_empty() {
+        return Err(PaletteError::Empty { file });
+    }
+    Ok(Palette::new(colors))
+}
+
+// GIMP: "GIMP Palette" header, optional Name/Columns lines, then "R G B [name]"
+pub fn parse_gpl(src: &str, file: &str) -> Result<Vec<[u8; 3]>, PaletteError> {
+    let mut colors = Vec::new();
+    for (line_i, line) in src.lines().enumerate() {
+        let err = |msg: String| PaletteError::Parse { file: file.to_owned(), line: line_i + 1, msg };
+        let line = line.trim();
+        if line_i == 0 {
+            if line != "GIMP Palette" {
+                return Err(err(format!("expected 'GIMP Palette', got '{}'", line)));
+            }
+            continue;
+        }
+        if line.is_empty() || line.starts_with('#') || line.starts_with("Name:") || line.starts_with("Columns:") {
+            continue;
+        }
+        let rgb: Vec<&str> = line.split_whitespace().take(3).collect();
+        colors.push(parse_rgb_words(&rgb).map_err(err)?);
+    }
+    Ok(colors)
+}
+
+// Lospec: one RRGGBB per line
+pub fn parse_hex_palette(src: &str, file: &str) -> Result<Vec<[u8; 3]>, PaletteError> {
+    let mut colors = Vec::new();
+    for (line_i, line) in src.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let hex = line.trim_start_matches('#');
+        let rgb = parse_hex_color(hex, 6)
+            .ok_or_else(|| PaletteError::Parse { file: file.to_owned(), line: line_i + 1, msg: format!("expected RRGGBB, got '{}'", line) })?;
+        colors.push([(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8]);
+    }
+    Ok(colors)
+}
+
+// JASC: "JASC-PAL", version "0100", color count, then "R G B"
+pub fn parse_jasc_pal(src: &str, file: &str) -> Result<Vec<[u8; 3]>, PaletteError> {
+    let mut lines = src.lines().map(str::trim).enumerate();
+    let mut header = |expected: &str| -> Result<(usize, &str), PaletteError> {
+        lines.next().ok_or_else(|| PaletteError::Parse { 
+            file: file.to_owned(), line: src.lines().count(), msg: format!("missing {}", expected) })
+    };
+    let err = |line_i: usize, msg: String| PaletteError::Parse { file: file.to_owned(), line: line_i + 1, msg };
+
+    let (line_i, magic) = header("'JASC-PAL'")?;
+    if magic != "JASC-PAL" {
+        return Err(err(line_i, format!("expected 'JASC-PAL', got '{}'", magic)));
+    }
+    let (line_i, version) = header("version")?;
+    if version != "0100" {
+        return Err(err(line_i, format!("unsupported version '{}'", version)));
+    }
+    let (line_i, count) = header("color count")?;
+    let count: usize = count.parse().map_err(|_| err(line_i, format!("bad color count '{}'", count)))?;
+
+    let mut colors = Vec::with_capacity(count);
+    for (line_i, line) in lines.filter(|(_, line)| !line.is_empty()) {
+        let rgb: Vec<&str> = line.split_whitespace().collect();
+        colors.push(parse_rgb_words(&rgb).map_err(|msg| err(line_i, msg))?);
+    }
+    if colors.len() != count {
+        return Err(err(src.lines().count().saturating_sub(1), format!("header says {} colors, found {}", count, colors.len())));
+    }
+    Ok(colors)
+}
+
+// Paint.NET: ';' comments, one AARRGGBB per line, alpha is ignored
+pub fn parse_paint_net_palette(src: &str, file: &str) -> Result<Vec<[u8; 3]>, PaletteError> {
+    let mut colors = Vec::new();
+    for (line_i, line) in src.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') {
+            continue;
+        }
+        let argb = parse_hex_color(line, 8)
+            .ok_or_else(|| PaletteError::Parse { file: file.to_owned(), line: line_i + 1, msg: format!("expected AARRGGBB, got '{}'", line) })?;
+        colors.push([(argb >> 16) as u8, (argb >> 8) as u8, argb as u8]);
+    }
+    Ok(colors)
+}
+
+// a 1xN or Nx1 image, colors in reading order
+pub fn palette_from_strip(image: &RgbaImage, file: &str) -> Result<Vec<[u8; 3]>, PaletteError> {
+    if image.size.x != 1 && image.size.y != 1 {
+        return Err(PaletteError::NotAStrip { file: file.to_owned(), size: image.size });
+    }
+    Ok(image.data.chunks_exact(4).map(|p| [p[0], p[1], p[2]]).collect())
+}
+
+fn parse_rgb_words(words: &[&str]) -> Result<[u8; 3], String> {
+    if words.len() < 3 {
+        return Err(format!("expected 'R G B', got '{}'", words.join(" ")));
+    }
+    let mut rgb = [0; 3];
+    for (c, word) in rgb.iter_mut().zip(words) {
+        *c = word.parse().map_err(|_| format!("'{}' is not a color channel in 0..=255", word))?;
+    }
+    Ok(rgb)
+}
+
+fn parse_hex_color(hex: &str, digits: usize) -> Option<u32> {
+    if hex.len() != digits || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    u32::from_str_radix(hex, 16).ok()
+}
+
+fn file_modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn file_stem(path: &Path) -> String {
+    path.file_stem().map_or_else(|| path.display().to_string(), |s| s.to_string_lossy().into_owned())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn storage_generation_follows_mutable_access() {
+        let mut storage = Storage::default();
+        let a = storage.add("a", 1);
+        let b = storage.add("b", 2);
+        assert_eq!(storage.generation(a), 0);
+
+        storage.get(a);
+        assert_eq!(storage.generation(a), 0);
+        storage.replace(a, 3);
+        assert_eq!(storage.generation(a), 1);
+        assert_eq!(storage.generation(b), 0);
+        for (_, item) in storage.iter_mut() {
+            *item += 1;
+        }
+        assert_eq!((storage.generation(a), storage.generation(b)), (2, 1));
+    }
+
+    #[test]
+    fn obj_ignores_extra_vertex_values() {
+        let src = "v 0 0 0 1\nv 1 0 0 1 0.5 0\nv 0 1 0\nf 1 2 3\n";
+        let groups = parse_obj(src, "test.obj").unwrap();
+        assert_eq!(groups[0].mesh.pos, vec![Vec3::ZERO, Vec3::X, Vec3::Y]);
+        assert!(matches!(parse_obj("v 0 0\n", "test.obj"), Err(ObjError::Parse { line: 1, .. })));
+    }
+
+    #[test]
+    fn obj_resolves_negative_indices() {
+        let src = "v 0 0 0\nv 1 0 0\nv 0 1 0\nvt 0 0\nvt 1 1\nf -3/-1 -2/-2 -1/-1\n";
+        let mesh = &parse_obj(src, "test.obj").unwrap()[0].mesh;
+        assert_eq!(mesh.pos, vec![Vec3::ZERO, Vec3::X, Vec3::Y]);
+        // v is flipped
+        assert_eq!(mesh.uv, vec![Vec2::new(1., 0.), Vec2::new(0., 1.), Vec2::new(1., 0.)]);
+        assert!(mesh.nor.is_empty());
+    }
+
+    #[test]
+    fn obj_fans_polygons() {
+        let src = "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0.5 2 0\nv 0 1 0\nf 1 2 3 4 5\n";
+        let mesh = &parse_obj(src, "test.obj").unwrap()[0].mesh;
+        assert_eq!(mesh.ind, vec![0, 1, 2, 0, 2, 3, 0, 3, 4]);
+    }
+
+    #[test]
+    fn obj_splits_groups() {
+        let src = "v 0 0 0\nv 1 0 0\nv 0 1 0\no first\nf 1 2 3\ng second part\nf 3 2 1\ng empty\n";
+        let groups = parse_obj(src, "test.obj").unwrap();
+        let names: Vec<_> = groups.iter().map(|g| g.name.as_str()).collect();
+        assert_eq!(names, ["first", "second part"]);
+        // every group has its own vertices
+        assert_eq!(groups[1].mesh.ind, vec![0, 1, 2]);
+        assert_eq!(groups[1].mesh.pos, vec![Vec3::Y, Vec3::X, Vec3::ZERO]);
+    }
+
+    #[test]
+    fn obj_errors_point_at_the_line() {
+        let line = |src: &str| match parse_obj(src, "test.obj") {
+            Err(ObjError::Parse { line, .. }) => line,
+            _ => panic!("expected a parse error"),
+        };
+        assert_eq!(line("v 0 0 0\n# comment\nf 1 2\n"), 3);
+        assert_eq!(line("v 0 0 0\n\nf 1 1 2\n"), 3);
+        assert_eq!(line("v 0 0 0\nv 0 x 0\n"), 2);
+        assert_eq!(line("v 0 0 0\nf 1 1 -2\n"), 2);
+        assert_eq!(line("v 0 0 0\nf 1 1 0\n"), 2);
+    }
+
+    #[test]
+    fn load_obj_adds_every_group() {
+        let path = std::env::temp_dir().join("pixel_load_obj_test.obj");
+        std::fs::write(&path, "v 0 0 0\nv 1 0 0\nv 0 1 0\no front\nf 1 2 3\ng back\nf 3 2 1\n").unwrap();
+        let mut assets = Assets::default();
+        let meshes = assets.load_obj(&path).unwrap();
+        assert_eq!(meshes.len(), 2);
+        assert_eq!(assets.meshes.handle("pixel_load_obj_test/front").unwrap(), meshes[0]);
+        assert_eq!(assets.meshes.get_by_name("pixel_load_obj_test/back").unwrap().pos, vec![Vec3::Y, Vec3::X, Vec3::ZERO]);
+        assert_eq!(assets.mesh_bounds(meshes[1]), Some((Vec3::ZERO, Vec3::new(1., 1., 0.))));
+        assert!(matches!(assets.load_obj(path.with_file_name("pixel_missing.obj")), Err(ObjError::Io(_))));
+    }
+
+    // a mesh with two triangles as separate primitives, drawn by a scaled parent and its rotated child.
+    // The image is never loaded
+    const GLTF_FIXTURE: &str = r#"{
+        "asset": { "version": "2.0" },
+        "scene": 0,
+        "scenes": [{ "nodes": [0] }],
+        "nodes": [
+            { "name": "parent", "mesh": 0, "translation": [1, 0, 0], "scale": [2, 2, 2], "children": [1] },
+            { "name": "child", "mesh": 0, "translation": [0, 1, 0], "rotation": [0, 0, 0.70710677, 0.70710677] }
+        ],
+        "meshes": [{
+            "name": "tris",
+            "primitives": [{ "attributes": { "POSITION": 0 } }, { "attributes": { "POSITION": 1 } }]
+        }],
+        "accessors": [
+            { "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3", "min": [0, 0, 0], "max": [1, 1, 0] },
+            { "bufferView": 0, "byteOffset": 36, "componentType": 5126, "count": 3, "type": "VEC3", "min": [0, 0, 1], "max": [1, 1, 1] }
+        ],
+        "bufferViews": [{ "buffer": 0, "byteLength": 72 }],
+        "buffers": [{
+            "byteLength": 72,
+            "uri": "data:application/octet-stream;base64,AAAAAAAAAAAAAAAAAACAPwAAAAAAAAAAAAAAAAAAgD8AAAAAAAAAAAAAAAAAAIA/AACAPwAAAAAAAIA/AAAAAAAAgD8AAIA/"
+        }],
+        "images": [{ "uri": "missing.png" }]
+    }"#;
+
+    #[test]
+    fn gltf_nodes_and_primitives() {
+        let path = std::env::temp_dir().join("pixel_gltf_test.gltf");
+        std::fs::write(&path, GLTF_FIXTURE).unwrap();
+        let mut assets = Assets::default();
+        let objects = assets.load_gltf(&path, Handle::dangling(0)).unwrap();
+
+        // the primitives are merged into one mesh
+        assert_eq!(assets.meshes.len(), 1);
+        let mesh = assets.meshes.get_by_name("pixel_gltf_test/tris").unwrap();
+        assert_eq!(mesh.pos.len(), 6);
+        assert_eq!(mesh.ind, vec![0, 1, 2, 3, 4, 5]);
+
+        assert_eq!(objects.len(), 2);
+        let (parent, child) = (&objects[0].transform, &objects[1].transform);
+        assert_eq!((objects[0].name.as_str(), objects[1].name.as_str()), ("parent", "child"));
+        assert_eq!(objects[1].mesh, objects[0].mesh);
+        assert_eq!(parent.position, Vec3::X);
+        assert_eq!(parent.rotation, Quat::IDENTITY);
+        assert_eq!(parent.scale, Vec3::splat(2.));
+        // the child is placed in the parent's space
+        assert!(child.position.abs_diff_eq(Vec3::new(1., 2., 0.), 1e-5), "{}", child.position);
+        assert!(child.rotation.abs_diff_eq(Quat::from_rotation_z(std::f32::consts::FRAC_PI_2), 1e-5), "{}", child.rotation);
+        assert!(child.scale.abs_diff_eq(Vec3::splat(2.), 1e-5), "{}", child.scale);
+    }
+
+    #[test]
+    fn shader_source_splits_stages() {
+        let src = "// header\n\n#shader vertex\nvoid main() {}\n#shader pixel\nout vec4 c;\nvoid main() {}\n";
+        let shader = ShaderSource::parse(src, "test.glsl").unwrap();
+        assert_eq!(shader.vertex.src, "void main() {}\n");
+        assert_eq!(shader.vertex.lines, vec![(0, 4)]);
+        assert_eq!(shader.fragment.src, "out vec4 c;\nvoid main() {}\n");
+        assert_eq!(shader.fragment.lines, vec![(0, 6), (0, 7)]);
+        assert!(shader.geometry.is_none());
+        assert_eq!(shader.files, vec!["test.glsl"]);
+    }
+
+    #[test]
+    fn shader_source_rejects_bad_sections() {
+        let line = |src: &str| match ShaderSource::parse(src, "test.glsl") {
+            Err(ShaderError::Parse { line, .. }) => line,
+            _ => panic!("expected a parse error"),
+        };
+        assert_eq!(line("#shader vertex\n#shader compute\n"), 2);
+        assert_eq!(line("#shader vertex\n#shader fragment\n#shader vertex\n"), 3);
+        assert_eq!(line("\nvoid main() {}\n#shader vertex\n"), 2);
+        assert_eq!(line("#shader vertex\n"), 1);
+    }
+
+    fn test_include(name: &str) -> Option<String> {
+        match name {
+            "common.glsl" => Some("uniform float u_a;\n#include \"inner.glsl\"\n".to_owned()),
+            "inner.glsl" => Some("uniform float u_b;\n".to_owned()),
+            "self.glsl" => Some("#include \"self.glsl\"\n".to_owned()),
+            "stage.glsl" => Some("#shader fragment\n".to_owned()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn shader_source_expands_includes() {
+        let src = "#shader vertex\n#version 140\n#include \"common.glsl\"\nvoid main() {}\n\
+            #shader fragment\n  #include \"inner.glsl\"\nvoid main() {}\n";
+        let shader = ShaderSource::parse_with(src, "shaders/test.glsl", &mut test_include).unwrap();
+        assert_eq!(shader.vertex.src, "#version 140\nuniform float u_a;\nuniform float u_b;\nvoid main() {}\n");
+        assert_eq!(shader.files, vec!["shaders/test.glsl", "shaders/common.glsl", "shaders/inner.glsl"]);
+        assert_eq!(shader.vertex.lines, vec![(0, 2), (1, 1), (2, 1), (0, 4)]);
+        // included twice, listed once
+        assert_eq!(shader.fragment.lines, vec![(2, 1), (0, 7)]);
+    }
+
+    #[test]
+    fn shader_source_rejects_bad_includes() {
+        let error = |src: &str| match ShaderSource::parse_with(src, "test.glsl", &mut test_include) {
+            Err(ShaderError::Parse { file, line, .. }) => (file, line),
+            _ => panic!("expected a parse error"),
+        };
+        let stages = |s: &str| format!("#shader vertex\n{}\n#shader fragment\n", s);
+        assert_eq!(error(&stages("#include \"missing.glsl\"")), ("test.glsl".to_owned(), 2));
+        assert_eq!(error(&stages("#include common.glsl")), ("test.glsl".to_owned(), 2));
+        assert_eq!(error(&stages("#include \"stage.glsl\"")), ("stage.glsl".to_owned(), 1));
+        assert_eq!(error(&stages("#include \"self.glsl\"")), ("self.glsl".to_owned(), 1));
+    }
+
+    #[test]
+    fn embedded_includes_resolve_without_the_files() {
+        for (name, src) in EMBEDDED_SHADER_INCLUDES {
+            let shader = format!("#shader vertex\n#include \"{}\"\n#shader fragment\n", name);
+            let shader = ShaderSource::parse(&shader, "missing/test.glsl").unwrap();
+            assert_eq!(shader.files[1], format!("missing/{}", name));
+            assert!(shader.vertex.src.lines().count() >= src.lines().count());
+            assert!(!shader.vertex.src.contains("#include"));
+        }
+    }
+
+    #[test]
+    fn defines_go_after_the_version() {
+        let src = "#shader vertex\n// stage\n#version 140\nvoid main() {}\n#shader fragment\nvoid main() {}\n";
+        let shader = ShaderSource::parse(src, "test.glsl").unwrap().with_define("INSTANCED");
+        assert_eq!(shader.vertex.src, "// stage\n#version 140\n#define INSTANCED\nvoid main() {}\n");
+        assert_eq!(shader.vertex.lines, vec![(0, 2), (0, 3), (0, 3), (0, 4)]);
+        // no #version, at the top
+        assert_eq!(shader.fragment.src, "#define INSTANCED\nvoid main() {}\n");
+        assert_eq!(shader.fragment.lines, vec![(0, 6), (0, 6)]);
+    }
+
+    #[test]
+    fn shader_log_lines_map_to_the_file() {
+        let files = ["lit.glsl".to_owned(), "scene.glsl".to_owned()];
+        let lines = [(0, 6), (1, 1), (1, 2), (0, 7)];
+        // mesa
+        assert_eq!(remap_shader_log("0:2(10): error: `x' undeclared", &files, &lines),
+            "scene.glsl:1(10): error: `x' undeclared\n");
+        // nvidia
+        assert_eq!(remap_shader_log("0(4) : error C1008: undefined variable \"x\"", &files, &lines),
+            "lit.glsl:7 : error C1008: undefined variable \"x\"\n");
+        assert_eq!(remap_shader_log("ERROR: 0:1: 'x' : undeclared identifier", &files, &lines),
+            "ERROR: lit.glsl:6: 'x' : undeclared identifier\n");
+        // only locations are touched, and only the ones inside the stage
+        assert_eq!(remap_shader_log("10:3 0x0 v0(1)\nwarning 0:9", &files, &lines), "10:3 0x0 v0(1)\nwarning 0:9\n");
+    }
+
+    #[test]
+    fn diffuse_shader_file_splits_and_maps_lines() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/diffuse.glsl");
+        let shader = load_shader_file(path).unwrap();
+        assert_eq!(shader.files, vec![path]);
+        assert!(shader.vertex.src.starts_with("#version 440 core\n"));
+        assert!(shader.vertex.src.contains("uniform mat4 u_mpv_mat;"));
+        assert!(!shader.vertex.src.contains("u_diffuse_coef"));
+        assert!(shader.fragment.src.contains("uniform float u_diffuse_coef;"));
+        assert!(shader.geometry.is_none());
+        // line 11 of the fragment stage is line 35 of the file
+        assert_eq!(remap_shader_log("0:11(15): error: `u_diffuse_coef' redeclared", &shader.files, &shader.fragment.lines),
+            format!("{}:35(15): error: `u_diffuse_coef' redeclared\n", path));
+        assert_eq!(remap_shader_log("0:10(14): error: `u_mpv_mat' redeclared", &shader.files, &shader.vertex.lines),
+            format!("{}:12(14): error: `u_mpv_mat' redeclared\n", path));
+    }
+
+    #[test]
+    fn watched_shader_reports_missing_file_and_reloads() {
+        let dir = std::env::temp_dir().join("pixel_watch_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("watched.glsl");
+        let src = "#shader vertex\nvoid main() {}\n#shader fragment\nvoid main() {}\n";
+        std::fs::write(&path, src).unwrap();
+
+        let mut assets = Assets::default();
+        let shader = assets.add_watched_shader("watched", &path, src).unwrap();
+        assets.reload_shaders(None);
+        assert_eq!(assets.shader_watches[0].error, None);
+
+        std::fs::remove_file(&path).unwrap();
+        assets.reload_shaders(None);
+        let error = assets.shader_watches[0].error.clone().unwrap();
+        assert!(error.contains("watched file is missing"), "{}", error);
+
+        let edited = "#shader vertex\nvoid main() {}\n#shader fragment\n// edited\nvoid main() {}\n";
+        std::fs::write(&path, edited).unwrap();
+        assets.reload_shaders(None);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(assets.shader_watches[0].error, None);
+        assert!(assets.shaders.get(shader).source.fragment.src.contains("// edited"));
+    }
+
+    #[test]
+    fn gpl_skips_header_and_names() {
+        let src = "GIMP Palette\nName: test\nColumns: 4\n# comment\n  0   0   0\tBlack\n255 128 1 Orange\n";
+        assert_eq!(parse_gpl(src, "test.gpl").unwrap(), vec![[0, 0, 0], [255, 128, 1]]);
+        assert!(parse_gpl("not a palette\n", "test.gpl").is_err());
+    }
+
+    #[test]
+    fn hex_accepts_optional_hash() {
+        assert_eq!(parse_hex_palette("1a1c2c\r\n#FFCD75\n\n", "test.hex").unwrap(), vec![[0x1a, 0x1c, 0x2c], [0xff, 0xcd, 0x75]]);
+        assert!(matches!(parse_hex_palette("fff\n", "test.hex"), Err(PaletteError::Parse { line: 1, .. })));
+    }
+
+    #[test]
+    fn jasc_checks_color_count() {
+        let src = "JASC-PAL\r\n0100\r\n2\r\n0 0 0\r\n255 255 255\r\n";
+        assert_eq!(parse_jasc_pal(src, "test.pal").unwrap(), vec![[0, 0, 0], [255, 255, 255]]);
+        assert!(parse_jasc_pal("JASC-PAL\n0100\n3\n0 0 0\n", "test.pal").is_err());
+        assert!(parse_jasc_pal("JASC-PAL\n0100\n1\n0 0 256\n", "test.pal").is_err());
+    }
+
+    #[test]
+    fn paint_net_drops_alpha() {
+        let src = "; paint.net palette\nFF102030\n80FFFFFF\n";
+        assert_eq!(parse_paint_net_palette(src, "test.txt").unwrap(), vec![[0x10, 0x20, 0x30], [255, 255, 255]]);
+    }
+
+    #[test]
+    fn strip_must_be_one_pixel_thick() {
+        let mut image = RgbaImage::new(UVec2::new(1, 2));
+        image.set_pixel(0, 1, [1, 2, 3, 255]);
+        assert_eq!(palette_from_strip(&image, "strip.png").unwrap(), vec![[0, 0, 0], [1, 2, 3]]);
+        assert!(palette_from_strip(&RgbaImage::new(UVec2::new(2, 2)), "strip.png").is_err());
+    }
+
+    #[test]
+    fn bundled_palettes_load() {
+        let mut assets = Assets::default();
+        let errors = assets.load_palette_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/assets/palettes"));
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(assets.palettes.get_by_name("sweetie-16").unwrap().len(), 16);
+    }
+}
@@ -1,5 +1,3 @@
-#![feature(vec_into_raw_parts, thread_local, ptr_internals)]
-
 #[macro_use]
 extern crate glium;
 
@@ -14,16 +12,41 @@ use glam::*;
 mod loading;
 mod game;
 mod draw;
-
-use loading::Assets;
-use draw::{RenderState, Render3dData};
+mod engine;
+mod headless;
+mod raster;
+mod palette;
+mod dither;
+mod outline;
+mod light;
+mod toon;
+mod shadow;
+mod material;
+
+#[cfg(test)]
+mod golden;
+
+use engine::Engine;
 
 fn main() {
+    let window_size = UVec2 {x: 1000, y: 1000};
+
+    // `pixel --screenshot out.png` writes the first frame without opening a window
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.as_slice() {
+        [flag, path] if flag == "--screenshot" => {
+            if let Err(e) = screenshot(path, window_size) {
+                eprintln!("{}: {}", path, e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        _ => (),
+    }
+
     let event_loop = glutin::event_loop::EventLoopBuilder::with_user_event().build();
 
-    let window_size = UVec2 {x: 1000, y: 1000};
-    RenderState::init(window_size, &event_loop);
-    Assets::init();
+    let mut engine = Engine::new(window_size, &event_loop);
     // unsafe{
     //     RenderState::init(RenderState {
     //         window_size: window_size.as_vec2(),
@@ -33,7 +56,6 @@ fn main() {
     //         render_buffer: RenderBuffer::new(&(*draw::render_state).display, 100),
     //     });
     // }
-    let rs = RenderState::get();
 
     //
     // let empty_texture
@@ -43,8 +65,6 @@ fn main() {
     // dbg!(std::mem::size_of::<u32>());
     // let texture = glium::texture::srgb_texture2d::SrgbTexture2d::new(&display, raw).unwrap();
 
-    // unsafe {*draw::render_state = }
-    game::init();
     let params = glium::DrawParameters {
         blend: glium::Blend::alpha_blending(),
         .. Default::default()
@@ -67,19 +87,19 @@ fn main() {
         // let mut redraw = || {
         // };
 
+        let rs = engine.render_state.as_mut().unwrap();
         match event {
             Event::MainEventsCleared => {
-                let gl_window = rs.display.gl_window();
                 // platform
                 //     .prepare_frame(imgui.io_mut(), gl_window.window())
                 //     .expect("Failed to prepare frame");
-                gl_window.window().request_redraw();
+                rs.request_redraw();
             }
             Event::RedrawRequested(_) => { 
                 if dt_dur >= ::std::time::Duration::new(0, 1_000_000_000u32 / 60) {
                     prev_frame_time = frame_begin_time;
-                    game::update(dt);
-                    game::render(dt, control_flow); 
+                    game::update(&mut engine, dt);
+                    game::render(&mut engine, dt, control_flow); 
                 }
             }
 
@@ -89,14 +109,23 @@ fn main() {
                     *control_flow = glutin::event_loop::ControlFlow::Exit;
                 }
 
-                rs.egui_glium.on_event(&event);
+                match &event {
+                    WindowEvent::Resized(size) => rs.resize(UVec2::new(size.width, size.height)),
+                    WindowEvent::ScaleFactorChanged { new_inner_size, .. } =>
+                        rs.resize(UVec2::new(new_inner_size.width, new_inner_size.height)),
+                    _ => (),
+                }
+
+                if let Some(egui_glium) = &mut rs.egui_glium {
+                    egui_glium.on_event(&event);
+                }
 
-                rs.display.gl_window().window().request_redraw(); // TODO(emilk): ask egui if the events warrants a repaint instead
+                rs.request_redraw(); // TODO(emilk): ask egui if the events warrants a repaint instead
             }
 
             glutin::event::Event::NewEvents(cause) => match cause {
                 glutin::event::StartCause::ResumeTimeReached { .. } 
-                    => rs.display.gl_window().window().request_redraw(),
+                    => rs.request_redraw(),
                 glutin::event::StartCause::Init => (),
                 _ => return,
             },
@@ -106,3 +135,15 @@ fn main() {
         // ::std::thread::sleep(::std::time::Duration::new(0, 1_000_000_000u32 / 60));
     });
 }
+
+// through the software rasterizer when there's no headless GL context
+fn screenshot(path: &str, size: UVec2) -> Result<(), png::EncodingError> {
+    let image = match Engine::headless(size) {
+        Ok(mut engine) => engine.render_to_image().unwrap(),
+        Err(e) => {
+            eprintln!("{}, falling back to the software renderer", e);
+            Engine::software().render_software(size)
+        }
+    };
+    image.save_png(path)
+}
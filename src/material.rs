@@ -0,0 +1,351 @@
+use std::rc::Rc;
+
+use glam::*;
+use glium::Program;
+use glium::texture::SrgbTexture2d;
+use glium::uniforms::{Uniforms, UniformValue, SamplerBehavior, MinifySamplerFilter, MagnifySamplerFilter, SamplerWrapFunction};
+
+use crate::draw::GlContext;
+use crate::headless::RgbaImage;
+use crate::loading::{Assets, Storage, TextureHandle, ShaderHandle};
+use crate::light::Shading;
+use crate::raster::srgb_to_linear;
+
+// an image in Assets::textures, the CPU copy is what the software renderer samples
+pub struct Texture {
+    pub image: Rc<RgbaImage>,
+    // None until Assets::create_gl_resources
+    pub gpu: Option<SrgbTexture2d>,
+}
+
+impl Texture {
+    pub fn new(image: RgbaImage) -> Self {
+        Texture { image: Rc::new(image), gpu: None }
+    }
+
+    // the first row of the image ends up at v = 0, the glTF convention
+    pub fn upload(&mut self, display: &GlContext) -> Result<(), glium::texture::TextureCreationError> {
+        let raw = glium::texture::RawImage2d::from_raw_rgba(self.image.data.clone(), self.image.size.into());
+        self.gpu = Some(SrgbTexture2d::with_mipmaps(display, raw, glium::texture::MipmapsOption::NoMipmap)?);
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextureFilter {
+    Nearest,
+    Linear,
+}
+
+impl TextureFilter {
+    pub const ALL: [TextureFilter; 2] = [TextureFilter::Nearest, TextureFilter::Linear];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TextureFilter::Nearest => "Nearest",
+            TextureFilter::Linear => "Linear",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextureWrap {
+    Repeat,
+    Clamp,
+}
+
+impl TextureWrap {
+    pub const ALL: [TextureWrap; 2] = [TextureWrap::Repeat, TextureWrap::Clamp];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TextureWrap::Repeat => "Repeat",
+            TextureWrap::Clamp => "Clamp",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sampler {
+    pub filter: TextureFilter,
+    pub wrap: TextureWrap,
+}
+
+impl Default for Sampler {
+    // crisp texels, the rest of the pipeline is about keeping pixels sharp
+    fn default() -> Self {
+        Sampler { filter: TextureFilter::Nearest, wrap: TextureWrap::Repeat }
+    }
+}
+
+impl Sampler {
+    pub fn behavior(&self) -> SamplerBehavior {
+        let (minify_filter, magnify_filter) = match self.filter {
+            TextureFilter::Nearest => (MinifySamplerFilter::Nearest, MagnifySamplerFilter::Nearest),
+            TextureFilter::Linear => (MinifySamplerFilter::Linear, MagnifySamplerFilter::Linear),
+        };
+        let wrap = match self.wrap {
+            TextureWrap::Repeat => SamplerWrapFunction::Repeat,
+            TextureWrap::Clamp => SamplerWrapFunction::Clamp,
+        };
+        SamplerBehavior { minify_filter, magnify_filter, wrap_function: (wrap, wrap, wrap), ..Default::default() }
+    }
+
+    // CPU version of the GL sampler, linear rgba, texel centers at +0.5 like GL
+    pub fn sample(&self, image: &RgbaImage, uv: Vec2) -> Vec4 {
+        let size = image.size.as_ivec2();
+        let texel = |x: i32, y: i32| -> Vec4 {
+            let (x, y) = match self.wrap {
+                TextureWrap::Repeat => (x.rem_euclid(size.x), y.rem_euclid(size.y)),
+                TextureWrap::Clamp => (x.clamp(0, size.x - 1), y.clamp(0, size.y - 1)),
+            };
+            let [r, g, b, a] = image.pixel(x as u32, y as u32);
+            let c = Vec4::new(r as f32, g as f32, b as f32, a as f32) / 255.;
+            Vec4::new(srgb_to_linear(c.x), srgb_to_linear(c.y), srgb_to_linear(c.z), c.w)
+        };
+        let p = uv * size.as_vec2();
+        match self.filter {
+            TextureFilter::Nearest => {
+                let p = p.floor().as_ivec2();
+                texel(p.x, p.y)
+            }
+            TextureFilter::Linear => {
+                let p = p - 0.5;
+                let base = p.floor();
+                let t = p - base;
+                let base = base.as_ivec2();
+                let top = texel(base.x, base.y).lerp(texel(base.x + 1, base.y), t.x);
+                let bottom = texel(base.x, base.y + 1).lerp(texel(base.x + 1, base.y + 1), t.x);
+                top.lerp(bottom, t.y)
+            }
+        }
+    }
+}
+
+// a value a material hands to its shader
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MaterialValue {
+    // linear rgb
+    Color(Vec3),
+    Float(f32),
+    // None still sets u_has_<name> so the shader can fall back
+    Texture(Option<TextureHandle>, Sampler),
+}
+
+// what assets/lit.glsl and assets/toon.glsl get for parameters a material doesn't have, or has with
+// the wrong type. A uniform that isn't set keeps the value of the last material drawn with the same program
+pub const STANDARD_PARAMS: [(&str, MaterialValue); 2] = [
+    ("tint", MaterialValue::Color(Vec3::ONE)),
+    ("base_color", MaterialValue::Texture(None, Sampler { filter: TextureFilter::Nearest, wrap: TextureWrap::Repeat })),
+];
+
+// an asset in Assets::materials, the shader plus the uniforms it gets for every object using it
+#[derive(Clone, Debug, PartialEq)]
+pub struct Material {
+    pub shader: ShaderHandle,
+    // uniform names without the u_ prefix, in the order the gui shows them
+    pub params: Vec<(String, MaterialValue)>,
+}
+
+impl Material {
+    // the parameters assets/lit.glsl and assets/toon.glsl read
+    pub fn standard(shader: ShaderHandle, tint: Vec3, base_color: Option<TextureHandle>) -> Self {
+        let mut material = Material { shader, params: Vec::new() };
+        material.set("tint", MaterialValue::Color(tint));
+        material.set("base_color", MaterialValue::Texture(base_color, Sampler::default()));
+        material
+    }
+
+    // falls back to STANDARD_PARAMS
+    pub fn get(&self, name: &str) -> Option<MaterialValue> {
+        match self.params.iter().find(|(n, _)| n == name) {
+            Some(&(_, value)) => Some(value),
+            None => STANDARD_PARAMS.iter().find(|(n, _)| *n == name).map(|&(_, value)| value),
+        }
+    }
+
+    // replaces the value of an existing parameter, the order stays the same
+    pub fn set(&mut self, name: &str, value: MaterialValue) {
+        match self.params.iter_mut().find(|(n, _)| n == name) {
+            Some((_, v)) => *v = value,
+            None => self.params.push((name.to_owned(), value)),
+        }
+    }
+
+    pub fn color(&self, name: &str) -> Option<Vec3> {
+        match self.get(name) {
+            Some(MaterialValue::Color(color)) => Some(color),
+            _ => None,
+        }
+    }
+
+    pub fn texture(&self, name: &str) -> Option<(TextureHandle, Sampler)> {
+        match self.get(name) {
+            Some(MaterialValue::Texture(Some(handle), sampler)) => Some((handle, sampler)),
+            _ => None,
+        }
+    }
+
+    // parameters whose type doesn't match the uniform `program` declares for them,
+    // MaterialUniforms leaves those out
+    pub fn mismatches<'a>(&'a self, program: &Program, textures: &Storage<Texture>) -> Vec<&'a str> {
+        self.params.iter()
+            .filter(|(name, value)| !fits(program, &param_uniforms(textures, name, value)))
+            .map(|(name, _)| name.as_str())
+            .collect()
+    }
+}
+
+// the uniforms a parameter is sent as
+fn param_uniforms<'t>(textures: &'t Storage<Texture>, name: &str, value: &MaterialValue) -> Vec<(String, UniformValue<'t>)> {
+    let uniform = format!("u_{}", name);
+    match *value {
+        MaterialValue::Color(color) => vec![(uniform, UniformValue::Vec3(color.to_array()))],
+        MaterialValue::Float(v) => vec![(uniform, UniformValue::Float(v))],
+        MaterialValue::Texture(handle, sampler) => {
+            // a texture that failed to upload is drawn like a missing one
+            let texture = handle.and_then(|handle| textures.get(handle).gpu.as_ref());
+            let mut uniforms = vec![(format!("u_has_{}", name), UniformValue::Bool(texture.is_some()))];
+            if let Some(texture) = texture {
+                uniforms.push((uniform, UniformValue::SrgbTexture2d(texture, Some(sampler.behavior()))));
+            }
+            uniforms
+        }
+    }
+}
+
+// glium fails the whole draw on a type mismatch, uniforms the program doesn't declare are skipped by it
+fn fits(program: &Program, uniforms: &[(String, UniformValue)]) -> bool {
+    uniforms.iter().all(|(name, value)| match program.get_uniform(name) {
+        Some(uniform) => value.is_usable_with(&uniform.ty),
+        None => true,
+    })
+}
+
+// the part of a material the software renderer mirrors, resolved up front
+// because the fragment closure can't borrow the assets
+#[derive(Clone, Debug)]
+pub struct CpuMaterial {
+    pub shading: Shading,
+    // linear rgb, multiplies the texture
+    pub tint: Vec3,
+    pub base_color: Option<(Rc<RgbaImage>, Sampler)>,
+}
+
+impl CpuMaterial {
+    // shaders without a CPU version are shaded like assets/lit.glsl
+    pub fn new(assets: &Assets, material: &Material) -> Self {
+        CpuMaterial {
+            shading: Shading::from_shader_name(assets.shaders.name(material.shader)),
+            // a tint of the wrong type is replaced by the default on the GPU as well
+            tint: material.color("tint").unwrap_or(Vec3::ONE),
+            base_color: material.texture("base_color")
+                .map(|(handle, sampler)| (assets.textures.get(handle).image.clone(), sampler)),
+        }
+    }
+
+    // linear rgb at `uv`, alpha is ignored, keep in sync with assets/lit.glsl
+    pub fn color_at(&self, uv: Vec2) -> Vec3 {
+        match &self.base_color {
+            Some((image, sampler)) => self.tint * sampler.sample(image, uv).xyz(),
+            None => self.tint,
+        }
+    }
+}
+
+// the material's parameters on top of the scene uniforms, set for every batch group
+// so the scene uniforms are borrowed instead of rebuilt
+pub struct MaterialUniforms<'a, U: Uniforms> {
+    pub material: &'a Material,
+    // what the parameters are checked against, see Material::mismatches
+    pub program: &'a Program,
+    pub textures: &'a Storage<Texture>,
+    pub uniforms: &'a U,
+}
+
+impl<'a, U: Uniforms> Uniforms for MaterialUniforms<'a, U> {
+    fn visit_values<'b, F: FnMut(&str, UniformValue<'b>)>(&'b self, mut f: F) {
+        let params = self.material.params.iter().map(|(name, value)| (name.as_str(), value));
+        let defaults = STANDARD_PARAMS.iter().map(|(name, value)| (*name, value));
+        let mut set: Vec<&str> = Vec::new();
+        for (name, value) in params.chain(defaults) {
+            if set.contains(&name) {
+                continue;
+            }
+            let uniforms = param_uniforms(self.textures, name, value);
+            if fits(self.program, &uniforms) {
+                for (uniform, value) in uniforms {
+                    f(&uniform, value);
+                }
+                set.push(name);
+            }
+        }
+        self.uniforms.visit_values(f);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loading::Handle;
+
+    // 2x1, black on the left, white on the right
+    fn image() -> RgbaImage {
+        let mut image = RgbaImage::new(UVec2::new(2, 1));
+        image.set_pixel(0, 0, [0, 0, 0, 255]);
+        image.set_pixel(1, 0, [255, 255, 255, 255]);
+        image
+    }
+
+    #[test]
+    fn nearest_picks_whole_texels() {
+        let sampler = Sampler::default();
+        assert_eq!(sampler.sample(&image(), Vec2::new(0.49, 0.5)), Vec4::new(0., 0., 0., 1.));
+        assert_eq!(sampler.sample(&image(), Vec2::new(0.51, 0.5)), Vec4::ONE);
+        // wraps around
+        assert_eq!(sampler.sample(&image(), Vec2::new(1.25, 0.5)), Vec4::new(0., 0., 0., 1.));
+    }
+
+    #[test]
+    fn linear_blends_between_texel_centers() {
+        let sampler = Sampler { filter: TextureFilter::Linear, wrap: TextureWrap::Clamp };
+        let mid = sampler.sample(&image(), Vec2::new(0.5, 0.5));
+        assert!(mid.abs_diff_eq(Vec4::new(0.5, 0.5, 0.5, 1.), 1e-6));
+        // clamped past the last center
+        assert_eq!(sampler.sample(&image(), Vec2::new(0.9, 0.5)), Vec4::ONE);
+    }
+
+    #[test]
+    fn set_replaces_in_place() {
+        let mut material = Material { shader: Handle::dangling(0), params: Vec::new() };
+        material.set("tint", MaterialValue::Color(Vec3::ONE));
+        material.set("roughness", MaterialValue::Float(0.5));
+        material.set("tint", MaterialValue::Color(Vec3::X));
+        assert_eq!(material.params.len(), 2);
+        assert_eq!(material.params[0].0, "tint");
+        assert_eq!(material.color("tint"), Some(Vec3::X));
+        // wrong type or missing
+        assert_eq!(material.color("roughness"), None);
+        assert_eq!(material.texture("base_color"), None);
+    }
+
+    #[test]
+    fn standard_params_have_defaults() {
+        let mut material = Material { shader: Handle::dangling(0), params: Vec::new() };
+        assert_eq!(material.color("tint"), Some(Vec3::ONE));
+        assert_eq!(material.get("base_color"), Some(MaterialValue::Texture(None, Sampler::default())));
+        assert_eq!(material.get("roughness"), None);
+        // the wrong type doesn't fall back, the caller decides
+        material.set("tint", MaterialValue::Float(0.5));
+        assert_eq!(material.color("tint"), None);
+    }
+
+    #[test]
+    fn texture_is_multiplied_by_the_tint() {
+        let untextured = CpuMaterial { shading: Shading::Lit, tint: Vec3::new(1., 0.5, 0.), base_color: None };
+        assert_eq!(untextured.color_at(Vec2::ZERO), untextured.tint);
+        let textured = CpuMaterial { base_color: Some((Rc::new(image()), Sampler::default())), ..untextured };
+        assert_eq!(textured.color_at(Vec2::new(0.25, 0.5)), Vec3::ZERO);
+        assert_eq!(textured.color_at(Vec2::new(0.75, 0.5)), textured.tint);
+    }
+}
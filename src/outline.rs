@@ -0,0 +1,143 @@
+use glam::*;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutlineColor {
+    // the pixel's own color scaled by this, in linear rgb
+    Darken(f32),
+    // fixed linear rgb
+    Ink(Vec3),
+}
+
+impl OutlineColor {
+    pub fn apply(&self, base: Vec4) -> Vec4 {
+        match *self {
+            OutlineColor::Darken(factor) => (base.xyz() * factor).extend(base.w),
+            OutlineColor::Ink(ink) => ink.extend(1.),
+        }
+    }
+
+    // the layout assets/outline.glsl expects
+    pub fn to_uniform(&self) -> [f32; 4] {
+        match *self {
+            OutlineColor::Darken(factor) => [factor, 0., 0., 0.],
+            OutlineColor::Ink(ink) => ink.extend(1.).to_array(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OutlineSettings {
+    pub enabled: bool,
+    // edges against anything further away, in depth buffer units
+    pub depth_threshold: f32,
+    // creases between faces bending more than this, in radians
+    pub normal_threshold: f32,
+    pub silhouette_color: OutlineColor,
+    pub crease_color: OutlineColor,
+}
+
+impl Default for OutlineSettings {
+    fn default() -> Self {
+        OutlineSettings {
+            enabled: false,
+            depth_threshold: 0.0002,
+            normal_threshold: 0.7,
+            silhouette_color: OutlineColor::Ink(Vec3::ZERO),
+            crease_color: OutlineColor::Darken(0.5),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Edge {
+    Silhouette,
+    Crease,
+}
+
+// keep in sync with assets/outline.glsl.
+// Edges are only marked on the nearer side so lines stay one pixel wide.
+pub fn classify(settings: &OutlineSettings, depth: f32, normal: Vec3, neighbours: &[(f32, Vec3)]) -> Option<Edge> {
+    let cos_threshold = settings.normal_threshold.cos();
+    let mut crease = false;
+    for &(n_depth, n_normal) in neighbours {
+        let further = n_depth - depth;
+        if further > settings.depth_threshold {
+            return Some(Edge::Silhouette);
+        }
+        if further >= 0. && normal.dot(n_normal) < cos_threshold {
+            crease = true;
+        }
+    }
+    crease.then(|| Edge::Crease)
+}
+
+// CPU reference of the outline pass, buffers are the low-res target's with rows top to bottom
+pub fn apply(colors: &mut [Vec4], depth: &[f32], normals: &[Vec3], size: UVec2, settings: &OutlineSettings) {
+    let source = colors.to_vec();
+    for y in 0..size.y {
+        for x in 0..size.x {
+            let i = (y * size.x + x) as usize;
+            // nothing was drawn here
+            if depth[i] >= 1. {
+                continue;
+            }
+            // clamped at the border, which makes the pixel its own neighbour
+            let neighbours = [(1, 0), (-1, 0), (0, 1), (0, -1)].map(|(dx, dy): (i32, i32)| {
+                let nx = (x as i32 + dx).clamp(0, size.x as i32 - 1) as u32;
+                let ny = (y as i32 + dy).clamp(0, size.y as i32 - 1) as u32;
+                let j = (ny * size.x + nx) as usize;
+                (depth[j], normals[j])
+            });
+            match classify(settings, depth[i], normals[i], &neighbours) {
+                Some(Edge::Silhouette) => colors[i] = settings.silhouette_color.apply(source[i]),
+                Some(Edge::Crease) => colors[i] = settings.crease_color.apply(source[i]),
+                None => (),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INK: Vec3 = Vec3::new(1., 0., 0.);
+
+    fn settings() -> OutlineSettings {
+        OutlineSettings {
+            enabled: true,
+            silhouette_color: OutlineColor::Ink(INK),
+            crease_color: OutlineColor::Darken(0.5),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn silhouette_is_drawn_on_the_near_side() {
+        // a 2 pixel wide object at depth 0.5 in the middle of a 4x1 row of background
+        let size = UVec2::new(4, 1);
+        let depth = [1., 0.5, 0.5, 1.];
+        let normals = [Vec3::Z; 4];
+        let mut colors = vec![Vec4::ZERO, Vec4::ONE, Vec4::ONE, Vec4::ZERO];
+        apply(&mut colors, &depth, &normals, size, &settings());
+        assert_eq!(colors, vec![Vec4::ZERO, INK.extend(1.), INK.extend(1.), Vec4::ZERO]);
+    }
+
+    #[test]
+    fn crease_between_faces_is_one_pixel() {
+        // two faces meeting at a right angle at the same depth
+        let size = UVec2::new(4, 1);
+        let depth = [0.5, 0.5, 0.5001, 0.5001];
+        let normals = [Vec3::Z, Vec3::Z, Vec3::X, Vec3::X];
+        let mut colors = vec![Vec4::ONE; 4];
+        apply(&mut colors, &depth, &normals, size, &settings());
+        let dark = Vec4::new(0.5, 0.5, 0.5, 1.);
+        assert_eq!(colors, vec![Vec4::ONE, dark, Vec4::ONE, Vec4::ONE]);
+    }
+
+    #[test]
+    fn flat_surface_has_no_edges() {
+        let neighbours = [(0.5, Vec3::Z); 4];
+        assert_eq!(classify(&settings(), 0.5, Vec3::Z, &neighbours), None);
+    }
+}
@@ -0,0 +1,129 @@
+use glam::*;
+
+use crate::raster::srgb_to_linear;
+
+// fixed set of colors the pixelated output gets snapped to
+#[derive(Clone, Debug, PartialEq)]
+pub struct Palette {
+    // sRGB, the way palette files store them
+    colors: Vec<[u8; 3]>,
+    // the same colors linear and in OKLab, cached for the nearest color search
+    linear: Vec<Vec3>,
+    lab: Vec<Vec3>,
+}
+
+impl Palette {
+    pub fn new(colors: Vec<[u8; 3]>) -> Self {
+        let linear: Vec<Vec3> = colors.iter()
+            .map(|c| Vec3::new(c[0] as f32, c[1] as f32, c[2] as f32) / 255.)
+            .map(|c| Vec3::new(srgb_to_linear(c.x), srgb_to_linear(c.y), srgb_to_linear(c.z)))
+            .collect();
+        let lab = linear.iter().map(|&c| linear_srgb_to_oklab(c)).collect();
+        Palette { colors, linear, lab }
+    }
+
+    // 0xRRGGBB
+    pub fn from_hex(colors: &[u32]) -> Self {
+        Palette::new(colors.iter().map(|&c| [(c >> 16) as u8, (c >> 8) as u8, c as u8]).collect())
+    }
+
+    pub fn colors(&self) -> &[[u8; 3]] {
+        &self.colors
+    }
+
+    pub fn linear(&self) -> &[Vec3] {
+        &self.linear
+    }
+
+    pub fn lab(&self) -> &[Vec3] {
+        &self.lab
+    }
+
+    pub fn len(&self) -> usize {
+        self.colors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.colors.is_empty()
+    }
+
+    // closest entry to a linear color by euclidean distance in OKLab
+    pub fn nearest_index(&self, color: Vec3) -> Option<usize> {
+        self.nearest_index_lab(linear_srgb_to_oklab(color))
+    }
+
+    pub fn nearest_index_lab(&self, lab: Vec3) -> Option<usize> {
+        self.lab.iter()
+            .map(|&p| p.distance_squared(lab))
+            .enumerate()
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(i, _)| i)
+    }
+
+    // linear in, linear out, an empty palette leaves the color alone
+    pub fn quantize(&self, color: Vec3) -> Vec3 {
+        self.nearest_index(color).map_or(color, |i| self.linear[i])
+    }
+}
+
+// https://bottosson.github.io/posts/oklab/, keep in sync with assets/palette.glsl
+pub fn linear_srgb_to_oklab(c: Vec3) -> Vec3 {
+    let c = c.max(Vec3::ZERO);
+    let l = 0.4122214708 * c.x + 0.5363325363 * c.y + 0.0514459929 * c.z;
+    let m = 0.2119034982 * c.x + 0.6806995451 * c.y + 0.1073969566 * c.z;
+    let s = 0.0883024619 * c.x + 0.2817188376 * c.y + 0.6299787005 * c.z;
+    let (l, m, s) = (l.cbrt(), m.cbrt(), s.cbrt());
+    Vec3::new(
+        0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s,
+        1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s,
+        0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s)
+}
+
+// what the game starts with, more can be loaded into Assets::palettes
+pub fn builtin_palettes() -> Vec<(&'static str, Palette)> {
+    vec![
+        ("1-bit", Palette::from_hex(&[0x000000, 0xffffff])),
+        ("Game Boy", Palette::from_hex(&[0x0f380f, 0x306230, 0x8bac0f, 0x9bbc0f])),
+        ("PICO-8", Palette::from_hex(&[
+            0x000000, 0x1d2b53, 0x7e2553, 0x008751, 0xab5236, 0x5f574f, 0xc2c3c7, 0xfff1e8,
+            0xff004d, 0xffa300, 0xffec27, 0x00e436, 0x29adff, 0x83769c, 0xff77a8, 0xffccaa,
+        ])),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oklab_reference_values() {
+        // white and black from the reference implementation
+        assert!(linear_srgb_to_oklab(Vec3::ONE).abs_diff_eq(Vec3::new(1., 0., 0.), 1e-3));
+        assert!(linear_srgb_to_oklab(Vec3::ZERO).abs_diff_eq(Vec3::ZERO, 1e-6));
+        let red = linear_srgb_to_oklab(Vec3::X);
+        assert!(red.abs_diff_eq(Vec3::new(0.6279, 0.2249, 0.1258), 1e-3));
+    }
+
+    #[test]
+    fn palette_colors_map_to_themselves() {
+        for (_, palette) in builtin_palettes() {
+            for (i, &c) in palette.linear().iter().enumerate() {
+                assert_eq!(palette.nearest_index(c), Some(i));
+            }
+        }
+    }
+
+    #[test]
+    fn quantize_picks_perceptually_nearest() {
+        let palette = Palette::from_hex(&[0x000000, 0xffffff]);
+        // a quarter of linear intensity is already past the perceptual midpoint
+        assert_eq!(palette.quantize(Vec3::splat(0.25)), Vec3::ONE);
+        assert_eq!(palette.quantize(Vec3::splat(0.05)), Vec3::ZERO);
+    }
+
+    #[test]
+    fn empty_palette_keeps_color() {
+        let palette = Palette::new(Vec::new());
+        assert_eq!(palette.quantize(Vec3::splat(0.3)), Vec3::splat(0.3));
+    }
+}
@@ -0,0 +1,411 @@
+use std::rc::Rc;
+
+use glam::*;
+
+use crate::draw::*;
+use crate::headless::RgbaImage;
+use crate::loading::MaterialHandle;
+use crate::palette::Palette;
+use crate::dither::{self, DitherSettings};
+use crate::outline::{self, OutlineSettings};
+use crate::shadow::{ShadowMap, ShadowSettings};
+
+// what the fragment stage of the software path gets to see, interpolated over the triangle
+#[derive(Clone, Copy, Debug)]
+pub struct Fragment {
+    pub position: Vec3,
+    pub normal: Vec3,
+    pub uv: Vec2,
+    // window space depth in 0..1, same as gl_FragCoord.z
+    pub depth: f32,
+    // of the batch group being drawn
+    pub material: MaterialHandle,
+    // shadow map lookup for the shadowed light, 1 when lit or without a shadow pass
+    pub shadow: f32,
+}
+
+// same output as assets/depth.glsl
+pub fn shade_depth(frag: &Fragment) -> Vec4 {
+    Vec4::new(frag.depth, frag.depth, frag.depth, 1.0)
+}
+
+// CPU rasterizer following the GL conventions the glium path uses:
+// near plane clipping, IfLess depth test, alpha blending, pixel centers at +0.5.
+// Colors are kept linear and only encoded to sRGB in `to_image`, like an sRGB framebuffer.
+pub struct SoftwareRenderer {
+    size: UVec2,
+    // rows top to bottom
+    color: Vec<Vec4>,
+    depth: Vec<f32>,
+    // interpolated normal of the nearest fragment, for the outline pass
+    normal: Vec<Vec3>,
+    pub pixelation: PixelationSettings,
+    // CPU reference of the palette post pass
+    pub palette: Option<Palette>,
+    pub dither: DitherSettings,
+    pub outline: OutlineSettings,
+    // the scene shader, shared with the low-res target of `pixelated`
+    pub shade: Rc<dyn Fn(&Fragment) -> Vec4>,
+    pub shadow: ShadowSettings,
+    // side of the map `shadow_pass` renders, see ShadowSettings::map_size
+    pub shadow_map_size: u32,
+    shadow_map: Option<Rc<ShadowMap>>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct ClipVertex {
+    clip: Vec4,
+    position: Vec3,
+    normal: Vec3,
+    uv: Vec2,
+}
+
+impl ClipVertex {
+    fn lerp(&self, other: &ClipVertex, t: f32) -> ClipVertex {
+        ClipVertex {
+            clip: self.clip.lerp(other.clip, t),
+            position: self.position.lerp(other.position, t),
+            normal: self.normal.lerp(other.normal, t),
+            uv: self.uv.lerp(other.uv, t),
+        }
+    }
+}
+
+impl SoftwareRenderer {
+    pub fn new(size: UVec2) -> Self {
+        let len = (size.x * size.y) as usize;
+        SoftwareRenderer {
+            size,
+            color: vec![Vec4::ZERO; len],
+            depth: vec![1.0; len],
+            normal: vec![Vec3::ZERO; len],
+            pixelation: PixelationSettings::default(),
+            palette: None,
+            dither: DitherSettings::default(),
+            outline: OutlineSettings::default(),
+            shade: Rc::new(shade_depth),
+            shadow: ShadowSettings::default(),
+            shadow_map_size: ShadowSettings::default().resolution,
+            shadow_map: None,
+        }
+    }
+
+    pub fn color(&self, x: u32, y: u32) -> Vec4 {
+        self.color[(y * self.size.x + x) as usize]
+    }
+
+    pub fn depth(&self, x: u32, y: u32) -> f32 {
+        self.depth[(y * self.size.x + x) as usize]
+    }
+
+    pub fn to_image(&self) -> RgbaImage {
+        let mut image = RgbaImage::new(self.size);
+        for y in 0..self.size.y {
+            for x in 0..self.size.x {
+                let c = self.color(x, y).clamp(Vec4::ZERO, Vec4::ONE);
+                let encode = |v: f32| (linear_to_srgb(v) * 255. + 0.5) as u8;
+                image.set_pixel(x, y, [encode(c.x), encode(c.y), encode(c.z), (c.w * 255. + 0.5) as u8]);
+            }
+        }
+        image
+    }
+
+    fn blend(&mut self, index: usize, src: Vec4) {
+        let dst = self.color[index];
+        self.color[index] = src * src.w + dst * (1. - src.w);
+    }
+
+    fn draw_triangle(&mut self, tri: [ClipVertex; 3], group: &BatchGroup) {
+        // only the near plane is clipped, the rest is handled by the bounding box
+        let mut poly = Vec::with_capacity(4);
+        for i in 0..3 {
+            let a = tri[i];
+            let b = tri[(i + 1) % 3];
+            let da = a.clip.z + a.clip.w;
+            let db = b.clip.z + b.clip.w;
+            if da >= 0. {
+                poly.push(a);
+            }
+            if (da >= 0.) != (db >= 0.) {
+                poly.push(a.lerp(&b, da / (da - db)));
+            }
+        }
+        for i in 1..poly.len().saturating_sub(1) {
+            self.raster_triangle([poly[0], poly[i], poly[i + 1]], group);
+        }
+    }
+
+    fn raster_triangle(&mut self, tri: [ClipVertex; 3], group: &BatchGroup) {
+        let size = self.size.as_vec2();
+        let mut screen = [Vec3::ZERO; 3];
+        let mut inv_w = [0.; 3];
+        for i in 0..3 {
+            let clip = tri[i].clip;
+            if clip.w <= 0. {
+                return;
+            }
+            inv_w[i] = 1. / clip.w;
+            let ndc = clip.xyz() * inv_w[i];
+            screen[i] = Vec3::new(
+                (ndc.x * 0.5 + 0.5) * size.x,
+                (0.5 - ndc.y * 0.5) * size.y,
+                ndc.z * 0.5 + 0.5);
+        }
+
+        let edge = |a: Vec3, b: Vec3, p: Vec2| (b.x - a.x) * (p.y - a.y) - (b.y - a.y) * (p.x - a.x);
+        let area = edge(screen[0], screen[1], screen[2].xy());
+        if area.abs() <= f32::EPSILON {
+            return;
+        }
+
+        let min = screen[0].xy().min(screen[1].xy()).min(screen[2].xy()).floor().max(Vec2::ZERO);
+        let max = screen[0].xy().max(screen[1].xy()).max(screen[2].xy()).ceil().min(size);
+        if min.x >= max.x || min.y >= max.y {
+            return;
+        }
+
+        for y in min.y as u32..max.y as u32 {
+            for x in min.x as u32..max.x as u32 {
+                let p = Vec2::new(x as f32 + 0.5, y as f32 + 0.5);
+                // barycentrics, sign of the area takes care of both windings
+                let b0 = edge(screen[1], screen[2], p) / area;
+                let b1 = edge(screen[2], screen[0], p) / area;
+                let b2 = edge(screen[0], screen[1], p) / area;
+                if b0 < 0. || b1 < 0. || b2 < 0. {
+                    continue;
+                }
+
+                // depth is linear in screen space, attributes need the perspective divide
+                let depth = b0 * screen[0].z + b1 * screen[1].z + b2 * screen[2].z;
+                let index = (y * self.size.x + x) as usize;
+                if !(depth < self.depth[index]) || depth < 0. {
+                    continue;
+                }
+
+                let p0 = b0 * inv_w[0];
+                let p1 = b1 * inv_w[1];
+                let p2 = b2 * inv_w[2];
+                let norm = 1. / (p0 + p1 + p2);
+                let mut frag = Fragment {
+                    position: (tri[0].position * p0 + tri[1].position * p1 + tri[2].position * p2) * norm,
+                    normal: ((tri[0].normal * p0 + tri[1].normal * p1 + tri[2].normal * p2) * norm).normalize_or_zero(),
+                    uv: (tri[0].uv * p0 + tri[1].uv * p1 + tri[2].uv * p2) * norm,
+                    depth,
+                    material: group.material,
+                    shadow: 1.,
+                };
+                if let Some(shadow_map) = &self.shadow_map {
+                    frag.shadow = shadow_map.visibility(frag.position);
+                }
+
+                self.depth[index] = depth;
+                self.normal[index] = frag.normal;
+                let color = (self.shade)(&frag);
+                self.blend(index, color);
+            }
+        }
+    }
+}
+
+impl Renderer for SoftwareRenderer {
+    fn size(&self) -> UVec2 {
+        self.size
+    }
+
+    fn clear(&mut self, color: Vec4, depth: f32) {
+        self.color.fill(color);
+        self.depth.fill(depth);
+        self.normal.fill(Vec3::ZERO);
+    }
+
+    fn draw_batch(&mut self, batch: &Render3dBatch, view_proj: Mat4) {
+        debug_assert!(batch.instance_groups.is_empty(), "the software renderer doesn't draw instances");
+        let vertex = |i: u32| -> Option<ClipVertex> {
+            let position = Vec3::from(batch.pos.get(i as usize)?.position);
+            let normal = batch.nor.get(i as usize).map_or(Vec3::ZERO, |n| Vec3::from(n.normal));
+            let uv = batch.uv.get(i as usize).map_or(Vec2::ZERO, |t| Vec2::from(t.uv));
+            Some(ClipVertex { clip: view_proj * position.extend(1.), position, normal, uv })
+        };
+
+        for group in batch.groups.iter() {
+            for tri in batch.ind[group.indices.clone()].chunks_exact(3) {
+                // out of range indices are dropped like a GL driver with robust access would
+                if let (Some(a), Some(b), Some(c)) = (vertex(tri[0]), vertex(tri[1]), vertex(tri[2])) {
+                    self.draw_triangle([a, b, c], group);
+                }
+            }
+        }
+    }
+
+    // it has no meshes to instance, render3d merges everything into the batch instead
+    fn instancing(&self) -> bool {
+        false
+    }
+
+    fn shadow_pass(&mut self, batch: &Render3dBatch, light_view_proj: Mat4) {
+        let size = self.shadow_map_size.max(1);
+        let mut light = SoftwareRenderer::new(UVec2::splat(size));
+        light.clear(Vec4::ZERO, 1.);
+        light.draw_batch(batch, light_view_proj);
+        self.shadow_map = Some(Rc::new(ShadowMap {
+            size,
+            depth: light.depth,
+            view_proj: light_view_proj,
+            pcf_radius: self.shadow.pcf_radius(),
+            bias: self.shadow.bias,
+        }));
+    }
+
+    fn pixelated(&mut self, texel_offset: Vec2, draw: &mut dyn FnMut(&mut dyn Renderer)) {
+        let layout = self.pixelation.layout(self.size);
+        let mut low_res = SoftwareRenderer::new(layout.texture_size());
+        low_res.shade = self.shade.clone();
+        low_res.shadow_map = self.shadow_map.clone();
+        low_res.clear(Vec4::ZERO, 1.);
+
+        draw(&mut low_res);
+
+        if self.outline.enabled {
+            outline::apply(&mut low_res.color, &low_res.depth, &low_res.normal, low_res.size, &self.outline);
+        }
+        if let Some(palette) = &self.palette {
+            dither::quantize(&mut low_res.color, low_res.size, palette, self.dither);
+        }
+
+        // nearest upscale into the viewport, blended over what's already there like the quad pass
+        let origin = layout.viewport_origin;
+        let viewport = layout.viewport_size.min(self.size - origin.min(self.size));
+        let to_target = layout.target_size.as_vec2() / layout.viewport_size.as_vec2();
+        // rows count down here while the offset is in GL's up
+        let shift = Vec2::new(-texel_offset.x, texel_offset.y) + layout.margin as f32;
+        let max = (low_res.size - 1).as_vec2();
+        for y in 0..viewport.y {
+            for x in 0..viewport.x {
+                let p = (Vec2::new(x as f32, y as f32) + 0.5) * to_target + shift;
+                let src = p.floor().clamp(Vec2::ZERO, max).as_uvec2();
+                let src = low_res.color(src.x, src.y);
+                self.blend(((origin.y + y) * self.size.x + origin.x + x) as usize, src);
+            }
+        }
+    }
+}
+
+pub fn srgb_to_linear(v: f32) -> f32 {
+    if v <= 0.04045 { v / 12.92 } else { ((v + 0.055) / 1.055).powf(2.4) }
+}
+
+pub fn linear_to_srgb(v: f32) -> f32 {
+    if v <= 0.0031308 { v * 12.92 } else { 1.055 * v.powf(1. / 2.4) - 0.055 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loading::Handle;
+
+    fn quad_batch(z: f32) -> Render3dBatch {
+        let mut batch = Render3dBatch::default();
+        for p in [[-1., -1., z], [1., -1., z], [1., 1., z], [-1., 1., z]] {
+            batch.pos.push(MeshRenderDataVertexPos { position: p });
+            batch.nor.push(MeshRenderDataVertexNor { normal: [0., 0., 1.] });
+        }
+        batch.ind.extend([0, 1, 2, 0, 2, 3]);
+        batch.push_group(Handle::dangling(0), 0);
+        batch
+    }
+
+    #[test]
+    fn fullscreen_quad_covers_every_pixel() {
+        let mut r = SoftwareRenderer::new(UVec2::new(8, 6));
+        r.clear(Vec4::ZERO, 1.);
+        r.draw_batch(&quad_batch(0.), Mat4::IDENTITY);
+        for y in 0..6 {
+            for x in 0..8 {
+                assert!((r.depth(x, y) - 0.5).abs() < 1e-5);
+                assert!(r.color(x, y).abs_diff_eq(Vec4::new(0.5, 0.5, 0.5, 1.), 1e-5));
+            }
+        }
+    }
+
+    #[test]
+    fn depth_test_keeps_nearest() {
+        let mut r = SoftwareRenderer::new(UVec2::new(4, 4));
+        r.clear(Vec4::ZERO, 1.);
+        r.draw_batch(&quad_batch(-0.5), Mat4::IDENTITY);
+        r.draw_batch(&quad_batch(0.5), Mat4::IDENTITY);
+        assert!((r.depth(1, 1) - 0.25).abs() < 1e-5);
+    }
+
+    #[test]
+    fn triangle_behind_near_plane_is_clipped() {
+        let mut r = SoftwareRenderer::new(UVec2::new(4, 4));
+        r.clear(Vec4::ZERO, 1.);
+        r.draw_batch(&quad_batch(-2.), Mat4::IDENTITY);
+        assert!(r.color.iter().all(|&c| c == Vec4::ZERO));
+    }
+
+    #[test]
+    fn uvs_are_interpolated() {
+        let mut r = SoftwareRenderer::new(UVec2::new(4, 4));
+        let mut batch = quad_batch(0.);
+        for uv in [[0., 1.], [1., 1.], [1., 0.], [0., 0.]] {
+            batch.uv.push(MeshRenderDataVertexUv { uv });
+        }
+        r.shade = Rc::new(|frag| frag.uv.extend(0.).extend(1.));
+        r.clear(Vec4::ZERO, 1.);
+        r.draw_batch(&batch, Mat4::IDENTITY);
+        // pixel centers of a 4x4 target, rows top to bottom like the uvs
+        assert!(r.color(0, 0).abs_diff_eq(Vec4::new(0.125, 0.125, 0., 1.), 1e-5));
+        assert!(r.color(3, 2).abs_diff_eq(Vec4::new(0.875, 0.625, 0., 1.), 1e-5));
+    }
+
+    #[test]
+    fn out_of_range_indices_are_skipped() {
+        let mut r = SoftwareRenderer::new(UVec2::new(4, 4));
+        let mut batch = quad_batch(0.);
+        batch.ind = vec![0, 1, 7];
+        batch.groups.clear();
+        batch.push_group(Handle::dangling(0), 0);
+        r.clear(Vec4::ZERO, 1.);
+        r.draw_batch(&batch, Mat4::IDENTITY);
+        assert!(r.depth.iter().all(|&d| d == 1.));
+    }
+
+    #[test]
+    fn integer_letterbox_leaves_bars() {
+        // 4x2 target fits twice into 10x5, centered with a one pixel bar left and right
+        let mut r = SoftwareRenderer::new(UVec2::new(10, 5));
+        r.pixelation = PixelationSettings {
+            resolution: PixelResolution::Fixed(UVec2::new(4, 2)),
+            scaling: PixelScaling::IntegerLetterbox,
+            snap: false,
+        };
+        let bar = Vec4::new(1., 0., 0., 1.);
+        r.clear(bar, 1.);
+        r.pixelated(Vec2::ZERO, &mut |low_res| low_res.draw_batch(&quad_batch(0.), Mat4::IDENTITY));
+        for y in 0..5 {
+            for x in 0..10 {
+                let inside = (1..9).contains(&x) && y < 4;
+                assert_eq!(r.color(x, y) != bar, inside, "pixel {} {}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn palette_pass_leaves_only_palette_colors() {
+        let palette = Palette::from_hex(&[0x0f380f, 0x9bbc0f]);
+        let mut r = SoftwareRenderer::new(UVec2::new(8, 8));
+        r.palette = Some(palette.clone());
+        r.clear(Vec4::ZERO, 1.);
+        r.pixelated(Vec2::ZERO, &mut |low_res| low_res.draw_batch(&quad_batch(0.), Mat4::IDENTITY));
+        assert!(r.color.iter().all(|c| palette.linear().contains(&c.xyz())));
+    }
+
+    #[test]
+    fn srgb_roundtrip() {
+        for i in 0..=255 {
+            let v = i as f32 / 255.;
+            assert!((linear_to_srgb(srgb_to_linear(v)) - v).abs() < 1e-4);
+        }
+    }
+}
@@ -0,0 +1,230 @@
+use glam::*;
+use glium::texture::DepthTexture2d;
+use glium::uniforms::{Uniforms, UniformValue, SamplerBehavior, MinifySamplerFilter, MagnifySamplerFilter};
+
+use crate::loading::Assets;
+use crate::game::GameObject;
+use crate::light::{LightKind, SceneLight};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ShadowSettings {
+    pub enabled: bool,
+    // side of the square shadow map in texels
+    pub resolution: u32,
+    // averages a 3x3 block of depth tests, softer edges
+    pub pcf: bool,
+    // while pixelated the map gets the low-res target's resolution and a single sample,
+    // so shadow edges step like the rest of the pixel art
+    pub hard_pixel: bool,
+    // against shadow acne, in light depth units (0..1)
+    pub bias: f32,
+}
+
+impl Default for ShadowSettings {
+    fn default() -> Self {
+        ShadowSettings { enabled: true, resolution: 1024, pcf: true, hard_pixel: false, bias: 0.002 }
+    }
+}
+
+impl ShadowSettings {
+    // `low_res` is the pixelated target's size, None when not pixelated
+    pub fn map_size(&self, low_res: Option<UVec2>) -> u32 {
+        match low_res {
+            Some(size) if self.hard_pixel => size.max_element().max(1),
+            _ => self.resolution.max(1),
+        }
+    }
+
+    // half the side of the filter kernel
+    pub fn pcf_radius(&self) -> i32 {
+        if self.pcf && !self.hard_pixel { 1 } else { 0 }
+    }
+}
+
+fn box_corners((min, max): (Vec3, Vec3)) -> [Vec3; 8] {
+    [0, 1, 2, 3, 4, 5, 6, 7].map(|i| Vec3::select(BVec3::new(i & 1 != 0, i & 2 != 0, i & 4 != 0), max, min))
+}
+
+// world space box around every mesh, None when there's nothing to draw.
+// Goes over the corners of each mesh's model space box, so it can be a bit loose for rotated meshes
+pub fn scene_bounds(assets: &Assets, game_objects: &[GameObject]) -> Option<(Vec3, Vec3)> {
+    let mut bounds: Option<(Vec3, Vec3)> = None;
+    for go in game_objects.iter() {
+        let local = match go.mesh.and_then(|handle| assets.mesh_bounds(handle)) {
+            Some(local) => local,
+            None => continue,
+        };
+        let model = go.transform.model();
+        for corner in box_corners(local) {
+            let p = model.transform_point3(corner);
+            bounds = Some(bounds.map_or((p, p), |(min, max)| (min.min(p), max.max(p))));
+        }
+    }
+    bounds
+}
+
+// orthographic frustum along the light that just contains the box
+pub fn light_view_proj(towards_light: Vec3, bounds: (Vec3, Vec3)) -> Mat4 {
+    let (min, max) = bounds;
+    let center = (min + max) * 0.5;
+    let radius = (max - min).length() * 0.5;
+    let up = if towards_light.y.abs() > 0.99 { Vec3::Z } else { Vec3::Y };
+    let view = Mat4::look_at_rh(center + towards_light * radius, center, up);
+
+    let mut view_min = Vec3::splat(f32::INFINITY);
+    let mut view_max = Vec3::splat(f32::NEG_INFINITY);
+    for corner in box_corners(bounds) {
+        let p = view.transform_point3(corner);
+        view_min = view_min.min(p);
+        view_max = view_max.max(p);
+    }
+    // flat scenes would give an empty frustum
+    let pad = Vec3::splat(1e-3);
+    let (view_min, view_max) = (view_min - pad, view_max + pad);
+    // the view looks down -Z
+    let proj = Mat4::orthographic_rh_gl(view_min.x, view_max.x, view_min.y, view_max.y, -view_max.z, -view_min.z);
+    proj * view
+}
+
+// marks the first directional light as the one casting the shadow and fits its frustum,
+// None when shadows are off or there's nothing to cast or receive them
+pub fn shadow_view_proj(lights: &mut [SceneLight], settings: &ShadowSettings,
+    assets: &Assets, game_objects: &[GameObject]) -> Option<Mat4>
+{
+    if !settings.enabled {
+        return None;
+    }
+    let bounds = scene_bounds(assets, game_objects)?;
+    let caster = lights.iter_mut().find(|l| l.kind == LightKind::Directional)?;
+    caster.shadowed = true;
+    Some(light_view_proj(caster.vector, bounds))
+}
+
+// CPU shadow map, filled by SoftwareRenderer::shadow_pass
+#[derive(Clone, Debug)]
+pub struct ShadowMap {
+    pub size: u32,
+    // rows top to bottom like the software renderer's buffers
+    pub depth: Vec<f32>,
+    pub view_proj: Mat4,
+    pub pcf_radius: i32,
+    pub bias: f32,
+}
+
+impl ShadowMap {
+    // 0 fully in shadow, 1 fully lit, keep in sync with shadow_visibility in assets/scene_fragment.glsl
+    pub fn visibility(&self, position: Vec3) -> f32 {
+        let p = self.view_proj.project_point3(position) * 0.5 + 0.5;
+        // outside the frustum nothing can be in front of it
+        if p.cmplt(Vec3::ZERO).any() || p.cmpgt(Vec3::ONE).any() {
+            return 1.;
+        }
+        let size = self.size as i32;
+        let texel = (p.xy() * self.size as f32).as_ivec2();
+        let r = self.pcf_radius;
+        let mut lit = 0;
+        for dy in -r..=r {
+            for dx in -r..=r {
+                let t = (texel + IVec2::new(dx, dy)).clamp(IVec2::ZERO, IVec2::splat(size - 1));
+                // GL counts texture rows from the bottom
+                let depth = self.depth[((size - 1 - t.y) * size + t.x) as usize];
+                if p.z - self.bias <= depth {
+                    lit += 1;
+                }
+            }
+        }
+        lit as f32 / ((2 * r + 1) * (2 * r + 1)) as f32
+    }
+}
+
+// the shadow on top of the scene uniforms
+pub struct ShadowUniforms<'a, U: Uniforms> {
+    // None without a shadow pass this frame
+    pub view_proj: Option<Mat4>,
+    pub map: &'a DepthTexture2d,
+    pub settings: &'a ShadowSettings,
+    pub uniforms: U,
+}
+
+impl<'a, U: Uniforms> Uniforms for ShadowUniforms<'a, U> {
+    fn visit_values<'b, F: FnMut(&str, UniformValue<'b>)>(&'b self, mut f: F) {
+        if let Some(view_proj) = self.view_proj {
+            let behavior = SamplerBehavior {
+                minify_filter: MinifySamplerFilter::Nearest,
+                magnify_filter: MagnifySamplerFilter::Nearest,
+                ..Default::default()
+            };
+            f("u_light_view_proj", UniformValue::Mat4(view_proj.to_cols_array_2d()));
+            f("u_shadow_map", UniformValue::DepthTexture2d(self.map, Some(behavior)));
+            f("u_shadow_pcf", UniformValue::SignedInt(self.settings.pcf_radius()));
+            f("u_shadow_bias", UniformValue::Float(self.settings.bias));
+        }
+        self.uniforms.visit_values(f);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::draw::Transform;
+    use crate::loading::Handle;
+
+    fn map(depth: f32, pcf_radius: i32) -> ShadowMap {
+        let bounds = (Vec3::splat(-1.), Vec3::splat(1.));
+        ShadowMap {
+            size: 4,
+            depth: vec![depth; 16],
+            view_proj: light_view_proj(Vec3::Y, bounds),
+            pcf_radius,
+            bias: 0.01,
+        }
+    }
+
+    #[test]
+    fn frustum_contains_the_bounds() {
+        let bounds = (Vec3::new(-1., 0., -2.), Vec3::new(3., 1., 0.5));
+        let view_proj = light_view_proj(Vec3::new(0.3, 1., 0.2).normalize(), bounds);
+        for corner in box_corners(bounds) {
+            let p = view_proj.project_point3(corner);
+            assert!(p.abs().cmple(Vec3::ONE).all(), "{} is outside at {}", corner, p);
+        }
+    }
+
+    #[test]
+    fn scene_bounds_follow_the_transform() {
+        let mut assets = Assets::default();
+        let cube = assets.add_mesh("cube", crate::game::cube_mesh());
+        let go = GameObject {
+            name: "cube".to_owned(),
+            transform: Transform { position: Vec3::X, scale: Vec3::splat(0.5), ..Transform::id() },
+            mesh: Some(cube),
+            material: Handle::dangling(0),
+            light: None,
+        };
+        assert_eq!(scene_bounds(&assets, &[go]), Some((Vec3::new(0.5, -0.5, -0.5), Vec3::new(1.5, 0.5, 0.5))));
+        assert_eq!(scene_bounds(&assets, &[]), None);
+    }
+
+    #[test]
+    fn occluded_points_are_in_shadow() {
+        // the light looks down -Y onto an occluder halfway through the box
+        let occluder = map(0.5, 0);
+        assert_eq!(occluder.visibility(Vec3::new(0., -0.9, 0.)), 0.);
+        assert_eq!(occluder.visibility(Vec3::new(0., 0.9, 0.)), 1.);
+        // past the frustum counts as lit
+        assert_eq!(occluder.visibility(Vec3::new(5., -0.9, 0.)), 1.);
+    }
+
+    #[test]
+    fn pcf_blends_across_an_edge() {
+        let mut shadow = map(1., 1);
+        // occluder over the left half of the map
+        for y in 0..4 {
+            for x in 0..2 {
+                shadow.depth[y * 4 + x] = 0.;
+            }
+        }
+        let v = shadow.visibility(Vec3::new(-0.1, 0., 0.));
+        assert!(v > 0. && v < 1., "{}", v);
+    }
+}
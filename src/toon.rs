@@ -0,0 +1,167 @@
+use glam::*;
+use glium::texture::Texture2d;
+use glium::uniforms::{Uniforms, UniformValue, SamplerBehavior, MinifySamplerFilter, MagnifySamplerFilter, SamplerWrapFunction};
+
+use crate::draw::GlContext;
+use crate::loading::PaletteHandle;
+use crate::palette::Palette;
+use crate::light::{LightKind, SceneLight};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ToonSettings {
+    // steps the diffuse term is cut into
+    pub bands: u32,
+    // looks the diffuse term up along these colors instead of the bands, darkest first
+    pub ramp: Option<PaletteHandle>,
+    // linear rgb
+    pub rim_color: Vec3,
+    pub rim_strength: f32,
+    // the rim covers where the normal is within this cosine of facing away from the camera
+    pub rim_width: f32,
+}
+
+impl Default for ToonSettings {
+    fn default() -> Self {
+        ToonSettings {
+            bands: 3,
+            ramp: None,
+            rim_color: Vec3::ONE,
+            rim_strength: 0.4,
+            rim_width: 0.25,
+        }
+    }
+}
+
+// anything lit gets at least the first step, keep in sync with assets/toon.glsl
+pub fn band(diffuse: f32, bands: u32) -> f32 {
+    let bands = bands.max(1) as f32;
+    (diffuse * bands).ceil().clamp(0., bands) / bands
+}
+
+// nearest entry, the ramp can't be empty
+pub fn ramp_color(ramp: &[Vec3], diffuse: f32) -> Vec3 {
+    let i = ((diffuse.max(0.) * ramp.len() as f32) as usize).min(ramp.len() - 1);
+    ramp[i]
+}
+
+// hard edged, either in the rim or not
+pub fn rim(settings: &ToonSettings, normal: Vec3, view_dir: Vec3) -> f32 {
+    if normal != Vec3::ZERO && normal.dot(view_dir) < settings.rim_width { 1. } else { 0. }
+}
+
+// CPU side of ToonUniforms
+#[derive(Clone, Debug)]
+pub struct ToonShader {
+    pub settings: ToonSettings,
+    // linear, empty when the bands are used
+    pub ramp: Vec<Vec3>,
+    pub view_dir: Vec3,
+}
+
+impl ToonShader {
+    // CPU version of assets/toon.glsl, linear rgb
+    pub fn shade(&self, lights: &[SceneLight], base_color: Vec3, position: Vec3, normal: Vec3, shadow: f32) -> Vec3 {
+        let normal = normal.normalize_or_zero();
+        let light = lights.iter().fold(Vec3::ZERO, |sum, l| {
+            // shadowed surfaces fall into the lowest band
+            let diffuse = l.diffuse(position, normal) * l.visibility(shadow);
+            sum + match l.kind {
+                LightKind::Ambient => l.radiance,
+                _ if !self.ramp.is_empty() => l.radiance * ramp_color(&self.ramp, diffuse),
+                _ => l.radiance * band(diffuse, self.settings.bands),
+            }
+        });
+        let rim = rim(&self.settings, normal, self.view_dir);
+        base_color * (light + self.settings.rim_color * self.settings.rim_strength * rim)
+    }
+}
+
+// the palette's linear colors as an N x 1 texture
+pub fn ramp_texture(display: &GlContext, palette: &Palette) -> Texture2d {
+    let data: Vec<f32> = palette.linear().iter().flat_map(|c| c.to_array()).collect();
+    let raw = glium::texture::RawImage2d::from_raw_rgb(data, (palette.len() as u32, 1));
+    Texture2d::with_format(display, raw,
+        glium::texture::UncompressedFloatFormat::F32F32F32,
+        glium::texture::MipmapsOption::NoMipmap).unwrap()
+}
+
+// the toon parameters on top of the scene uniforms, assets/lit.glsl just ignores them
+pub struct ToonUniforms<'a, U: Uniforms> {
+    pub settings: &'a ToonSettings,
+    pub ramp: Option<&'a Texture2d>,
+    // towards the camera, the same for every fragment which is exact for orthographic cameras
+    pub view_dir: Vec3,
+    pub uniforms: U,
+}
+
+impl<'a, U: Uniforms> Uniforms for ToonUniforms<'a, U> {
+    fn visit_values<'b, F: FnMut(&str, UniformValue<'b>)>(&'b self, mut f: F) {
+        f("u_bands", UniformValue::SignedInt(self.settings.bands.max(1) as i32));
+        f("u_rim_color", UniformValue::Vec3(self.settings.rim_color.to_array()));
+        f("u_rim_strength", UniformValue::Float(self.settings.rim_strength));
+        f("u_rim_width", UniformValue::Float(self.settings.rim_width));
+        f("u_view_dir", UniformValue::Vec3(self.view_dir.normalize_or_zero().to_array()));
+        match self.ramp {
+            Some(ramp) => {
+                let behavior = SamplerBehavior {
+                    minify_filter: MinifySamplerFilter::Nearest,
+                    magnify_filter: MagnifySamplerFilter::Nearest,
+                    wrap_function: (SamplerWrapFunction::Clamp, SamplerWrapFunction::Clamp, SamplerWrapFunction::Clamp),
+                    ..Default::default()
+                };
+                f("u_ramp", UniformValue::Texture2d(ramp, Some(behavior)));
+                f("u_ramp_size", UniformValue::SignedInt(ramp.width() as i32));
+            }
+            None => f("u_ramp_size", UniformValue::SignedInt(0)),
+        }
+        self.uniforms.visit_values(f);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::draw::Transform;
+    use crate::light::Light;
+
+    fn sun() -> SceneLight {
+        // shining straight down
+        let transform = Transform { rotation: Quat::from_rotation_arc(-Vec3::Z, -Vec3::Y), ..Transform::id() };
+        SceneLight::new(&Light::directional(Vec3::ONE, 1.), &transform)
+    }
+
+    #[test]
+    fn bands_step_the_diffuse_term() {
+        assert_eq!(band(0., 3), 0.);
+        assert_eq!(band(0.1, 3), 1. / 3.);
+        assert_eq!(band(0.5, 3), 2. / 3.);
+        assert_eq!(band(1., 3), 1.);
+        // a single band is plain lit or unlit
+        assert_eq!(band(0.01, 1), 1.);
+    }
+
+    #[test]
+    fn ramp_replaces_the_bands() {
+        let ramp = vec![Vec3::new(0.1, 0., 0.), Vec3::new(0., 1., 0.)];
+        let toon = ToonShader {
+            settings: ToonSettings { rim_strength: 0., ..Default::default() },
+            ramp: ramp.clone(),
+            view_dir: Vec3::Z,
+        };
+        let normal = Quat::from_rotation_z(0.3) * Vec3::Y;
+        assert_eq!(toon.shade(&[sun()], Vec3::ONE, Vec3::ZERO, normal, 1.), ramp[1]);
+        assert_eq!(toon.shade(&[sun()], Vec3::ONE, Vec3::ZERO, -Vec3::Y, 1.), ramp[0]);
+        // in shadow the lit side drops to the bottom of the ramp too
+        let shadowed = SceneLight { shadowed: true, ..sun() };
+        assert_eq!(toon.shade(&[shadowed], Vec3::ONE, Vec3::ZERO, normal, 0.), ramp[0]);
+    }
+
+    #[test]
+    fn rim_only_at_grazing_angles() {
+        let settings = ToonSettings::default();
+        assert_eq!(rim(&settings, Vec3::Z, Vec3::Z), 0.);
+        assert_eq!(rim(&settings, Vec3::X, Vec3::Z), 1.);
+        // meshes without normals don't get a rim all over
+        assert_eq!(rim(&settings, Vec3::ZERO, Vec3::Z), 0.);
+    }
+}